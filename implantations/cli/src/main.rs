@@ -13,9 +13,181 @@ fn main() {
             },
             "translate" => match std::env::args().nth(2) {
                 Some(file) => {
-                    compiler::VMTranslator::load(std::path::PathBuf::from(&file))
-                        .process()
-                        .write();
+                    let flags = std::env::args().skip(3).collect::<Vec<String>>();
+                    let os = os_path(&flags);
+                    if let Some(target) = flag_value(&flags, "--target") {
+                        translate_to(&file, &target, os);
+                        return;
+                    }
+                    let optimize = flags.iter().any(|f| f == "-O");
+                    let tree_shake = flags.iter().any(|f| f == "--tree-shake");
+                    let inline =
+                        flags.iter().position(|f| f == "--inline").map(|index| {
+                            match flags.get(index + 1) {
+                                Some(size) if !size.starts_with('-') => size
+                                    .parse::<usize>()
+                                    .expect("inline size should be a number"),
+                                _ => 8,
+                            }
+                        });
+                    let profile = flags.iter().any(|f| f == "--profile");
+                    let sources = load_vm_sources(&file, os);
+                    let cache_top = flags.iter().any(|f| f == "--cache-top");
+                    let sources = compiler::vm::as_str_pairs(&sources);
+                    let configure = |translator: &mut compiler::VMTranslator| {
+                        translator
+                            .compact(flags.iter().any(|f| f == "--compact"))
+                            .optimize(optimize)
+                            .annotate(flags.iter().any(|f| f == "--annotate"))
+                            .tree_shake(tree_shake)
+                            .fast_compare(flags.iter().any(|f| f == "--fast-compare"))
+                            .inline(inline.unwrap_or(0))
+                            .profile(profile)
+                            .extended_commands(flags.iter().any(|f| f == "--extended-vm"))
+                            .extended_isa(flags.iter().any(|f| f == "--extended-isa"))
+                            .stack_check(flags.iter().any(|f| f == "--stack-check"))
+                            .threads(
+                                flag_value(&flags, "--threads")
+                                    .map(|n| {
+                                        n.parse::<usize>().expect("threads should be a number")
+                                    })
+                                    .unwrap_or(0),
+                            );
+                    };
+                    let mut translator = compiler::VMTranslator::new();
+                    configure(&mut translator);
+                    let asm = translator
+                        .cache_top(cache_top)
+                        .translate(&sources)
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    if cache_top {
+                        let mut baseline = compiler::VMTranslator::new();
+                        configure(&mut baseline);
+                        let baseline = baseline
+                            .translate(&sources)
+                            .unwrap_or_else(|e| panic!("{}", e));
+                        report_savings(&baseline, &asm);
+                    }
+                    std::fs::write(output_path(&file, "asm"), asm).expect("failed to write file");
+                    if flags.iter().any(|f| f == "--source-map") {
+                        std::fs::write(
+                            output_path(&file, "map.json"),
+                            translator.source_map().to_json(),
+                        )
+                        .expect("failed to write file");
+                    }
+                    if profile {
+                        let counters = translator.profile_counters();
+                        let layout = counters
+                            .iter()
+                            .map(|counter| format!("{}\n", counter))
+                            .collect::<String>();
+                        let layout_path = output_path(&file, "profile");
+                        std::fs::write(&layout_path, layout).expect("failed to write file");
+                        println!(
+                            "{} profile counters in RAM[{}..{}], see {}",
+                            counters.len(),
+                            counters
+                                .first()
+                                .map_or(compiler::profile::PROFILE_END, |c| c.address),
+                            compiler::profile::PROFILE_END,
+                            layout_path.display()
+                        );
+                    }
+                    if optimize {
+                        println!(
+                            "peephole optimizer saved {} instructions",
+                            translator.saved_instructions()
+                        );
+                    }
+                    if cache_top {
+                        println!(
+                            "caching the top of the stack saved {} instructions",
+                            translator.cached_instructions()
+                        );
+                    }
+                    if inline.is_some() {
+                        println!("inlined {} calls", translator.inlined_calls());
+                    }
+                    if tree_shake {
+                        println!(
+                            "tree shaking removed {} functions",
+                            translator.removed_functions().len()
+                        );
+                    }
+                }
+                _ => println!("please provide a file"),
+            },
+            "profile" => match (std::env::args().nth(2), std::env::args().nth(3)) {
+                (Some(layout), Some(dump)) => {
+                    let counters = std::fs::read_to_string(layout)
+                        .expect("cannot read file")
+                        .lines()
+                        .map(|line| line.parse::<compiler::profile::Counter>())
+                        .collect::<Result<Vec<_>, String>>()
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    let ram = compiler::profile::parse_ram_dump(
+                        &std::fs::read_to_string(dump).expect("cannot read file"),
+                    )
+                    .unwrap_or_else(|e| panic!("{}", e));
+                    print!("{}", compiler::profile::report(&counters, &ram));
+                }
+                _ => println!("please provide a .profile file and a ram dump"),
+            },
+            "emulate" => match std::env::args().nth(2) {
+                Some(file) => {
+                    let steps = std::env::args()
+                        .nth(3)
+                        .map(|n| n.parse::<usize>().expect("steps should be a number"))
+                        .unwrap_or(1_000_000);
+                    let mut emulator = compiler::VmEmulator::load(std::path::PathBuf::from(&file))
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    if let Err(e) = emulator.run(steps) {
+                        println!("error after {} steps: {}", emulator.steps(), e);
+                    }
+                    print!("{}", emulator.dump_ram(0..compiler::emulator::SCREEN));
+                }
+                _ => println!("please provide a file"),
+            },
+            "callgraph" => match std::env::args().nth(2) {
+                Some(dir) => {
+                    let flags = std::env::args().skip(3).collect::<Vec<String>>();
+                    let graph = load_graph(&dir, os_path(&flags));
+                    if flags.iter().any(|f| f == "--json") {
+                        print!("{}", graph.to_json());
+                    } else {
+                        print!("{}", graph.to_dot());
+                    }
+                }
+                _ => println!("please provide a file"),
+            },
+            "lint" => match std::env::args().nth(2) {
+                Some(dir) => {
+                    let flags = std::env::args().skip(3).collect::<Vec<String>>();
+                    let warnings = lint(&dir, os_path(&flags));
+                    for warning in &warnings {
+                        println!("{}", warning);
+                    }
+                    if !warnings.is_empty() {
+                        println!("{} warnings", warnings.len());
+                        std::process::exit(1);
+                    }
+                }
+                _ => println!("please provide a file"),
+            },
+            "stack" => match std::env::args().nth(2) {
+                Some(dir) => {
+                    let flags = std::env::args().skip(3).collect::<Vec<String>>();
+                    let files = load_vm_commands(&dir, os_path(&flags));
+                    let files = files
+                        .iter()
+                        .map(|(name, commands)| (name.as_str(), commands.as_slice()))
+                        .collect::<Vec<_>>();
+                    let report = compiler::stack_depth::analyze(&files);
+                    print!("{}", report);
+                    if report.overflow_risk() {
+                        std::process::exit(1);
+                    }
                 }
                 _ => println!("please provide a file"),
             },
@@ -24,3 +196,150 @@ fn main() {
         _ => println!("please provide a cmd"),
     }
 }
+
+fn flag_value(flags: &[String], name: &str) -> Option<String> {
+    let index = flags.iter().position(|f| f == name)?;
+    flags.get(index + 1).cloned()
+}
+
+/// `--with-os <dir>` links the os in dir, a bare `--with-os` the one in
+/// $NAND2TETRIS_OS or else in the tools/OS of the current directory or the
+/// nearest parent having one
+fn os_path(flags: &[String]) -> Option<std::path::PathBuf> {
+    let index = flags.iter().position(|f| f == "--with-os")?;
+    match flags.get(index + 1) {
+        Some(dir) if !dir.starts_with('-') => Some(std::path::PathBuf::from(dir)),
+        _ => Some(default_os_path()),
+    }
+}
+
+fn default_os_path() -> std::path::PathBuf {
+    if let Some(dir) = std::env::var_os("NAND2TETRIS_OS") {
+        return dir.into();
+    }
+    let current = std::env::current_dir().expect("cannot read the current directory");
+    current
+        .ancestors()
+        .map(|dir| dir.join("tools/OS"))
+        .find(|dir| dir.is_dir())
+        .unwrap_or_else(|| {
+            panic!(
+                "no tools/OS in {} or above, pass --with-os <OS_DIR> or set NAND2TETRIS_OS",
+                current.display()
+            )
+        })
+}
+
+/// `<dir>/<dir name>.<extension>`, or the .vm file with the extension replaced
+fn output_path(path: &str, extension: &str) -> std::path::PathBuf {
+    let path = std::path::PathBuf::from(path);
+    let mut out_path = match path.file_name() {
+        Some(name) if path.is_dir() => path.join(name),
+        _ => path,
+    };
+    out_path.set_extension(extension);
+    out_path
+}
+
+/// the .vm files of dir, linked with the os if one is given
+fn load_vm_sources(dir: &str, os: Option<std::path::PathBuf>) -> Vec<(String, String)> {
+    let mut paths =
+        compiler::vm::vm_paths(std::path::Path::new(dir)).unwrap_or_else(|e| panic!("{}", e));
+    if let Some(os) = os {
+        let os_paths = compiler::vm::vm_paths(&os).unwrap_or_else(|e| panic!("{}", e));
+        paths = compiler::vm::link_library(paths, os_paths);
+    }
+    compiler::vm::read_sources(&paths).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// the parsed commands of every .vm file of dir, linked with the os if one is given
+fn load_vm_commands(
+    dir: &str,
+    os: Option<std::path::PathBuf>,
+) -> Vec<(String, Vec<(usize, compiler::Command)>)> {
+    load_vm_sources(dir, os)
+        .into_iter()
+        .map(|(name, source)| {
+            let commands =
+                compiler::vm::parse(&source).unwrap_or_else(|e| panic!("{}.vm {}", name, e));
+            (name, commands)
+        })
+        .collect()
+}
+
+fn load_graph(dir: &str, os: Option<std::path::PathBuf>) -> compiler::CallGraph {
+    let files = load_vm_commands(dir, os);
+    let files = files
+        .iter()
+        .map(|(name, commands)| (name.as_str(), commands.as_slice()))
+        .collect::<Vec<_>>();
+    compiler::CallGraph::new(&files)
+}
+
+fn lint(dir: &str, os: Option<std::path::PathBuf>) -> Vec<compiler::lint::Warning> {
+    let files = load_vm_commands(dir, os);
+    let files = files
+        .iter()
+        .map(|(name, commands)| (name.as_str(), commands.as_slice()))
+        .collect::<Vec<_>>();
+    compiler::lint::lint(&files)
+}
+
+/// cycles the cpu may run to halt before the comparison gives up
+const MAX_CYCLES: usize = 50_000_000;
+
+/// compare code size and cycles to halt of `asm` with the `baseline` translation
+fn report_savings(baseline: &str, asm: &str) {
+    let run = |asm: &str| -> (usize, Result<Option<usize>, String>) {
+        let hack = compiler::Assembler::new().process(asm.to_string());
+        let size = hack.lines().count();
+        let cycles = compiler::HackCpu::new(&hack)
+            .and_then(|mut cpu| Ok(cpu.run(MAX_CYCLES)?.then(|| cpu.cycles())));
+        (size, cycles)
+    };
+    let percent = |before: usize, after: usize| {
+        100.0 * (before as f64 - after as f64) / before.max(1) as f64
+    };
+    let (baseline_size, baseline_cycles) = run(baseline);
+    let (size, cycles) = run(asm);
+    println!(
+        "code size: {} -> {} instructions ({:.1}% smaller)",
+        baseline_size,
+        size,
+        percent(baseline_size, size)
+    );
+    match (baseline_cycles, cycles) {
+        (Ok(Some(before)), Ok(Some(after))) => println!(
+            "cycles to halt: {} -> {} ({:.1}% fewer)",
+            before,
+            after,
+            percent(before, after)
+        ),
+        (Err(e), _) | (_, Err(e)) => println!("no cycle comparison: {}", e),
+        _ => println!(
+            "no cycle comparison, the program did not halt within {} cycles",
+            MAX_CYCLES
+        ),
+    }
+}
+
+/// translate a directory of .vm files with one of the non hack backends
+fn translate_to(dir: &str, target: &str, os: Option<std::path::PathBuf>) {
+    let sources = load_vm_sources(dir, os);
+    let sources = compiler::vm::as_str_pairs(&sources);
+    let (code, extension) = match target {
+        "c" => (compiler::translator::c::translate(&sources), "c"),
+        "rust" => (compiler::translator::rust::translate(&sources), "rs"),
+        "wat" => (compiler::translator::wat::translate(&sources), "wat"),
+        "x86" => (compiler::translator::x86::translate(&sources), "s"),
+        _ => {
+            println!("no target {} is defined", target);
+            return;
+        }
+    };
+    std::fs::write(
+        output_path(dir, extension),
+        code.unwrap_or_else(|e| panic!("{}", e)),
+    )
+    .expect("failed to write file");
+}
@@ -0,0 +1,69 @@
+//! tokenizes the jack os of project 12 and every project 11 program, run with
+//! `cargo bench -p compiler`.
+use compiler::Tokenizer;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const ROUNDS: usize = 200;
+
+fn jack_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) {
+    let mut entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<PathBuf>>();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() && recursive {
+            jack_files(&path, recursive, files);
+        } else if path.extension().is_some_and(|ext| ext == "jack") {
+            files.push(path);
+        }
+    }
+}
+
+fn main() {
+    let projects = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../projects");
+    let mut files = vec![];
+    jack_files(&projects.join("12"), false, &mut files);
+    jack_files(&projects.join("11"), true, &mut files);
+    let sources = files
+        .iter()
+        .map(|path| std::fs::read_to_string(path).expect("failed to read jack file"))
+        .collect::<Vec<String>>();
+    let bytes = sources.iter().map(String::len).sum::<usize>();
+
+    bench("every token", &sources, bytes, |tokenizer| {
+        std::iter::from_fn(|| tokenizer.take_token_all_type()).count()
+    });
+    // the parser peeks at most one token ahead before taking it
+    bench("peek and take", &sources, bytes, |tokenizer| {
+        let mut tokens = 0;
+        while tokenizer.peek_token().is_some() {
+            tokenizer.take_token();
+            tokens += 1;
+        }
+        tokens
+    });
+}
+
+fn bench(name: &str, sources: &[String], bytes: usize, run: impl Fn(&mut Tokenizer) -> usize) {
+    let start = Instant::now();
+    let mut tokens = 0;
+    for _ in 0..ROUNDS {
+        for source in sources {
+            tokens += run(&mut Tokenizer::new(source));
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} files ({} bytes, {} tokens) {} times in {:.2?}, {:.1} MB/s, {:.0} ns per token",
+        name,
+        sources.len(),
+        bytes,
+        tokens / ROUNDS,
+        ROUNDS,
+        elapsed,
+        (bytes * ROUNDS) as f64 / elapsed.as_secs_f64() / 1e6,
+        elapsed.as_nanos() as f64 / tokens as f64
+    );
+}
@@ -6,6 +6,12 @@ pub struct Assembler {
     r_index: u32,
 }
 
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Assembler {
     pub fn new() -> Self {
         Assembler {
@@ -79,16 +85,19 @@ impl Assembler {
             None => {
                 // TODO: this there any better way to do this?
                 let parts: Vec<&str> = instruction.split(';').collect();
-                let rest = parts.get(0).unwrap_or(&"");
+                let rest = parts.first().unwrap_or(&"");
                 let jump = parts.get(1).unwrap_or(&"");
-                let jump_code = get_jump_code(jump).unwrap_or(&"");
+                let jump_code = get_jump_code(jump).unwrap_or("");
                 let parts_2 = rest.split('=').rev().collect::<Vec<&str>>();
-                let comp = parts_2.get(0).unwrap_or(&"");
+                let comp = parts_2.first().unwrap_or(&"");
                 let dest = parts_2.get(1).unwrap_or(&"");
                 let a_indicator_code = if comp.contains('M') { "1" } else { "0" };
                 let dest_code = get_dest_code(dest).unwrap_or("");
-                let comp_code = get_comp_code(comp).unwrap_or("");
-                ["111", a_indicator_code, comp_code, dest_code, jump_code].join("")
+                let (prefix, comp_code) = match get_shift_code(comp) {
+                    Some(shift_code) => ("101", shift_code),
+                    None => ("111", get_comp_code(comp).unwrap_or("")),
+                };
+                [prefix, a_indicator_code, comp_code, dest_code, jump_code].join("")
             }
         }
     }
@@ -114,16 +123,28 @@ pub fn get_comp_code(comp_cmd: &str) -> Option<&'static str> {
         "D-1" => Some("001110"),
         "A-1" => Some("110010"),
         "M-1" => Some("110010"),
-        "D+A" => Some("000010"),
-        "D+M" => Some("000010"),
+        "D+A" | "A+D" => Some("000010"),
+        "D+M" | "M+D" => Some("000010"),
         "D-A" => Some("010011"),
         "D-M" => Some("010011"),
         "A-D" => Some("000111"),
         "M-D" => Some("000111"),
-        "D&A" => Some("000000"),
-        "D&M" => Some("000000"),
-        "D|A" => Some("010101"),
-        "D|M" => Some("010101"),
+        "D&A" | "A&D" => Some("000000"),
+        "D&M" | "M&D" => Some("000000"),
+        "D|A" | "A|D" => Some("010101"),
+        "D|M" | "M|D" => Some("010101"),
+        _ => None,
+    }
+}
+
+/// shifts by one of the extended hack isa, encoded with `101` instead of `111`.
+/// `>>` keeps the sign.
+pub fn get_shift_code(comp_cmd: &str) -> Option<&'static str> {
+    match comp_cmd {
+        "D<<" => Some("110000"),
+        "A<<" | "M<<" => Some("100000"),
+        "D>>" => Some("010000"),
+        "A>>" | "M>>" => Some("000000"),
         _ => None,
     }
 }
@@ -187,7 +208,7 @@ pub fn to_address(num_like: &str) -> Option<String> {
 
 #[cfg(test)]
 mod tests {
-    use crate::assembler::{get_predefined_symbols, to_address, Assembler};
+    use crate::assembler::{get_comp_code, get_predefined_symbols, to_address, Assembler};
     use std::ffi::OsStr;
     use std::path::PathBuf;
 
@@ -214,6 +235,25 @@ mod tests {
         assert_eq!(to_address("2"), Some("000000000000010".to_string()));
     }
 
+    #[test]
+    fn test_shifts() {
+        let out = Assembler::new().process("D=D<<\nM=M>>\nAM=A<<;JGT\n".to_string());
+        assert_eq!(
+            out,
+            "1010110000010000\n1011000000001000\n1010100000101001\n"
+        );
+    }
+
+    #[test]
+    fn test_commuted_comp() {
+        let pairs = [("D+M", "M+D"), ("D+A", "A+D"), ("D&M", "M&D"), ("D|A", "A|D")];
+        for (comp, commuted) in pairs {
+            assert_eq!(get_comp_code(comp), get_comp_code(commuted));
+        }
+        let out = Assembler::new().process("M=M+D\n".to_string());
+        assert_eq!(out, "1111000010001000\n");
+    }
+
     fn compare(name: &str) {
         let mut asm_path =
             PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(OsStr::new("../../projects/06/"));
@@ -0,0 +1,331 @@
+use crate::source_map::json_string;
+use crate::vm::Command;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// where a vm function is defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Definition {
+    pub file: String,
+    pub line: usize,
+    pub n_locals: u16,
+}
+
+/// a single `call` command, `caller` is `None` for code outside of functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub caller: Option<String>,
+    pub callee: String,
+    pub n_args: u16,
+    pub file: String,
+    pub line: usize,
+}
+
+/// all calls from one function to another, `caller` is `None` for code outside
+/// of functions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge<'a> {
+    pub caller: Option<&'a str>,
+    pub callee: &'a str,
+    pub count: usize,
+    /// the distinct argument counts used at the call sites
+    pub n_args: BTreeSet<u16>,
+}
+
+/// every function and every call site of a set of vm files.
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    pub functions: BTreeMap<String, Definition>,
+    pub calls: Vec<Call>,
+}
+
+impl CallGraph {
+    /// build the graph from `(file stem, commands)` pairs as returned by `vm::parse`.
+    pub fn new(files: &[(&str, &[(usize, Command)])]) -> Self {
+        let mut graph = CallGraph::default();
+        for (file, commands) in files {
+            let mut caller = None;
+            for (line, command) in commands.iter() {
+                match command {
+                    Command::Function(name, n_locals) => {
+                        caller = Some(name.clone());
+                        graph.functions.insert(
+                            name.clone(),
+                            Definition {
+                                file: file.to_string(),
+                                line: *line,
+                                n_locals: *n_locals,
+                            },
+                        );
+                    }
+                    Command::Call(callee, n_args) => graph.calls.push(Call {
+                        caller: caller.clone(),
+                        callee: callee.clone(),
+                        n_args: *n_args,
+                        file: file.to_string(),
+                        line: *line,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        graph
+    }
+
+    /// the distinct functions called by `caller`.
+    pub fn callees(&self, caller: &str) -> BTreeSet<&str> {
+        self.calls
+            .iter()
+            .filter(|call| call.caller.as_deref() == Some(caller))
+            .map(|call| call.callee.as_str())
+            .collect()
+    }
+
+    /// call sites grouped by caller and callee.
+    pub fn edges(&self) -> Vec<Edge<'_>> {
+        let mut edges: BTreeMap<(Option<&str>, &str), Edge> = BTreeMap::new();
+        for call in &self.calls {
+            let caller = call.caller.as_deref();
+            let edge = edges
+                .entry((caller, call.callee.as_str()))
+                .or_insert_with(|| Edge {
+                    caller,
+                    callee: &call.callee,
+                    count: 0,
+                    n_args: BTreeSet::new(),
+                });
+            edge.count += 1;
+            edge.n_args.insert(call.n_args);
+        }
+        edges.into_values().collect()
+    }
+
+    /// defined functions without a single call site. `Sys.init` is called by the
+    /// bootstrap code and never listed.
+    pub fn uncalled(&self) -> Vec<&str> {
+        self.functions
+            .keys()
+            .filter(|name| *name != "Sys.init" && !self.calls.iter().any(|c| &c.callee == *name))
+            .map(|name| name.as_str())
+            .collect()
+    }
+
+    /// called functions that are not defined anywhere.
+    pub fn undefined(&self) -> BTreeSet<&str> {
+        self.calls
+            .iter()
+            .filter(|call| !self.functions.contains_key(&call.callee))
+            .map(|call| call.callee.as_str())
+            .collect()
+    }
+
+    /// graphviz dot, uncalled functions are filled yellow and undefined ones are
+    /// dashed red. edges are labelled `<calls>x (<args> args)`.
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec![
+            "digraph calls {".to_string(),
+            "  node [shape=box];".to_string(),
+        ];
+        let uncalled = self.uncalled();
+        for name in self.functions.keys() {
+            if uncalled.contains(&name.as_str()) {
+                lines.push(format!(
+                    "  {} [style=filled, fillcolor=yellow];",
+                    json_string(name)
+                ));
+            } else {
+                lines.push(format!("  {};", json_string(name)));
+            }
+        }
+        for name in self.undefined() {
+            lines.push(format!(
+                "  {} [style=dashed, color=red, fontcolor=red];",
+                json_string(name)
+            ));
+        }
+        let edges = self.edges();
+        if edges.iter().any(|edge| edge.caller.is_none()) {
+            lines.push(format!("  {} [shape=plaintext];", json_string(TOPLEVEL)));
+        }
+        for edge in &edges {
+            let color = if self.functions.contains_key(edge.callee) {
+                ""
+            } else {
+                ", color=red"
+            };
+            lines.push(format!(
+                "  {} -> {} [label=\"{}x ({} args)\"{}];",
+                json_string(edge.caller.unwrap_or(TOPLEVEL)),
+                json_string(edge.callee),
+                edge.count,
+                join(&edge.n_args, "/"),
+                color
+            ));
+        }
+        lines.push("}".to_string());
+        lines.join("\n") + "\n"
+    }
+
+    /// `{"functions":[..],"undefined":[..],"edges":[..]}`, a `null` caller stands
+    /// for code outside of functions.
+    pub fn to_json(&self) -> String {
+        let uncalled = self.uncalled();
+        let functions = self
+            .functions
+            .iter()
+            .map(|(name, definition)| {
+                format!(
+                    "{{\"name\":{},\"file\":{},\"line\":{},\"locals\":{},\"called\":{}}}",
+                    json_string(name),
+                    json_string(&definition.file),
+                    definition.line,
+                    definition.n_locals,
+                    !uncalled.contains(&name.as_str())
+                )
+            })
+            .collect::<Vec<String>>();
+        let undefined = self
+            .undefined()
+            .into_iter()
+            .map(json_string)
+            .collect::<Vec<String>>();
+        let edges = self
+            .edges()
+            .iter()
+            .map(|edge| {
+                format!(
+                    "{{\"caller\":{},\"callee\":{},\"calls\":{},\"args\":[{}]}}",
+                    edge.caller.map_or("null".to_string(), json_string),
+                    json_string(edge.callee),
+                    edge.count,
+                    join(&edge.n_args, ",")
+                )
+            })
+            .collect::<Vec<String>>();
+        format!(
+            "{{\n\"functions\":[{}],\n\"undefined\":[{}],\n\"edges\":[{}]\n}}\n",
+            functions.join(",\n"),
+            undefined.join(","),
+            edges.join(",\n")
+        )
+    }
+
+    /// every defined function `root` can end up calling, `root` included.
+    pub fn reachable(&self, root: &str) -> BTreeSet<String> {
+        let mut reached = BTreeSet::new();
+        let mut pending = vec![root];
+        while let Some(function) = pending.pop() {
+            if !self.functions.contains_key(function) || !reached.insert(function.to_string()) {
+                continue;
+            }
+            pending.extend(self.callees(function));
+        }
+        reached
+    }
+}
+
+/// node standing in for code outside of functions
+const TOPLEVEL: &str = "(toplevel)";
+
+fn join(values: &BTreeSet<u16>, separator: &str) -> String {
+    values
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm;
+
+    const MAIN: &str = "function Main.main 1\n\
+                        call Main.helper 0\n\
+                        call Math.abs 1\n\
+                        return\n\
+                        function Main.helper 0\n\
+                        call Main.helper 0\n\
+                        return\n\
+                        function Main.unused 0\n\
+                        call Main.main 0\n\
+                        return";
+
+    #[test]
+    fn test_graph() {
+        let commands = vm::parse(MAIN).unwrap();
+        let graph = CallGraph::new(&[("Main", &commands)]);
+        assert_eq!(graph.functions.len(), 3);
+        assert_eq!(graph.functions["Main.helper"].line, 5);
+        assert_eq!(graph.calls.len(), 4);
+        assert_eq!(
+            graph.callees("Main.main").into_iter().collect::<Vec<_>>(),
+            vec!["Main.helper", "Math.abs"]
+        );
+    }
+
+    #[test]
+    fn test_reachable() {
+        let commands = vm::parse(MAIN).unwrap();
+        let graph = CallGraph::new(&[("Main", &commands)]);
+        // undefined functions are not part of the result
+        assert_eq!(
+            graph.reachable("Main.main").into_iter().collect::<Vec<_>>(),
+            vec!["Main.helper", "Main.main"]
+        );
+        assert!(graph.reachable("Sys.init").is_empty());
+    }
+
+    #[test]
+    fn test_edges() {
+        let commands = vm::parse(MAIN).unwrap();
+        let graph = CallGraph::new(&[("Main", &commands)]);
+        let edges = graph.edges();
+        assert_eq!(edges.len(), 4);
+        let helper = edges
+            .iter()
+            .find(|e| e.caller == Some("Main.helper"))
+            .unwrap();
+        assert_eq!((helper.callee, helper.count), ("Main.helper", 1));
+        assert_eq!(graph.uncalled(), vec!["Main.unused"]);
+        assert_eq!(
+            graph.undefined().into_iter().collect::<Vec<_>>(),
+            vec!["Math.abs"]
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let commands = vm::parse(
+            "call Main.main 0\n\
+             function Main.main 0\n\
+             call Main.f 1\n\
+             call Main.f 2\n\
+             call Main.f 1\n\
+             call Foo.bar 0\n\
+             return\n\
+             function Main.f 0\n\
+             return",
+        )
+        .unwrap();
+        let graph = CallGraph::new(&[("Main", &commands)]);
+        assert_eq!(
+            graph.to_dot(),
+            "digraph calls {\n  node [shape=box];\n  \"Main.f\";\n  \"Main.main\";\n  \
+             \"Foo.bar\" [style=dashed, color=red, fontcolor=red];\n  \
+             \"(toplevel)\" [shape=plaintext];\n  \
+             \"(toplevel)\" -> \"Main.main\" [label=\"1x (0 args)\"];\n  \
+             \"Main.main\" -> \"Foo.bar\" [label=\"1x (0 args)\", color=red];\n  \
+             \"Main.main\" -> \"Main.f\" [label=\"3x (1/2 args)\"];\n}\n"
+        );
+        assert_eq!(
+            graph.to_json(),
+            "{\n\"functions\":[\
+             {\"name\":\"Main.f\",\"file\":\"Main\",\"line\":8,\"locals\":0,\"called\":true},\n\
+             {\"name\":\"Main.main\",\"file\":\"Main\",\"line\":2,\"locals\":0,\"called\":true}],\n\
+             \"undefined\":[\"Foo.bar\"],\n\"edges\":[\
+             {\"caller\":null,\"callee\":\"Main.main\",\"calls\":1,\"args\":[0]},\n\
+             {\"caller\":\"Main.main\",\"callee\":\"Foo.bar\",\"calls\":1,\"args\":[0]},\n\
+             {\"caller\":\"Main.main\",\"callee\":\"Main.f\",\"calls\":3,\"args\":[1,2]}]\n}\n"
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use crate::emulator::{KBD, RAM_SIZE};
+
+/// words of the instruction memory.
+const ROM_SIZE: usize = 32768;
+/// `0;JMP`, with the preceding `@address` of its own address the usual way to halt.
+const JUMP: u16 = 0b1110_1010_1000_0111;
+
+/// executes assembled `.hack` programs one instruction per cycle, including the
+/// shift instructions of the extended isa.
+///
+/// unlike the hardware, reading or writing `M` above `KBD` is an error, like it is
+/// in the cpu emulator.
+pub struct HackCpu {
+    rom: Vec<u16>,
+    ram: Vec<i16>,
+    a: u16,
+    d: i16,
+    pc: usize,
+    cycles: usize,
+}
+
+impl HackCpu {
+    /// `hack` holds one instruction per line as 16 binary digits, like the
+    /// assembler writes them.
+    pub fn new(hack: &str) -> Result<Self, String> {
+        let lines = hack
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<&str>>();
+        // labels past the rom assemble to addresses that do not fit in 15 bits
+        if lines.len() > ROM_SIZE {
+            return Err(format!(
+                "{} instructions do not fit in the rom of {}",
+                lines.len(),
+                ROM_SIZE
+            ));
+        }
+        let rom = lines
+            .into_iter()
+            .enumerate()
+            .map(|(index, line)| {
+                match line.len() {
+                    16 => u16::from_str_radix(line, 2).ok(),
+                    _ => None,
+                }
+                .ok_or(format!("line {}: invalid instruction {}", index + 1, line))
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+        Ok(HackCpu {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycles: 0,
+        })
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    pub fn peek(&self, address: usize) -> i16 {
+        self.ram[address]
+    }
+
+    pub fn poke(&mut self, address: usize, value: i16) {
+        self.ram[address] = value
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// true when the program ran off the end of the rom or spins in `@x` /
+    /// `0;JMP` at address x.
+    pub fn is_halted(&self) -> bool {
+        match (self.rom.get(self.pc), self.rom.get(self.pc + 1)) {
+            (None, _) => true,
+            (Some(a), Some(&JUMP)) => *a as usize == self.pc,
+            _ => false,
+        }
+    }
+
+    /// run until the program halts or for at most `cycles` cycles, returns
+    /// whether it halted.
+    pub fn run(&mut self, cycles: usize) -> Result<bool, String> {
+        let end = self.cycles + cycles;
+        while !self.is_halted() {
+            if self.cycles == end {
+                return Ok(false);
+            }
+            self.step()?;
+        }
+        Ok(true)
+    }
+
+    /// execute the instruction at the program counter.
+    pub fn step(&mut self) -> Result<(), String> {
+        let instruction = *self
+            .rom
+            .get(self.pc)
+            .ok_or(format!("pc {} is outside of the rom", self.pc))?;
+        self.cycles += 1;
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc += 1;
+            return Ok(());
+        }
+        let uses_m = instruction & 0x1000 != 0;
+        let y = match uses_m {
+            true => self.m()?,
+            false => self.a as i16,
+        };
+        let comp = (instruction >> 6) & 0x3f;
+        let out = match instruction >> 13 {
+            0b111 => alu(comp, self.d, y),
+            // shift instructions: left or right, of D or of A / M
+            0b101 => {
+                let x = if comp & 0b01_0000 != 0 { self.d } else { y };
+                match comp & 0b10_0000 != 0 {
+                    true => x << 1,
+                    false => x >> 1,
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "pc {}: invalid instruction {:016b}",
+                    self.pc, instruction
+                ))
+            }
+        };
+        if instruction & 0b1000 != 0 {
+            *self.m_mut()? = out;
+        }
+        if instruction & 0b10_0000 != 0 {
+            self.a = out as u16;
+        }
+        if instruction & 0b1_0000 != 0 {
+            self.d = out;
+        }
+        let jump = match out {
+            0 => instruction & 0b010 != 0,
+            out if out < 0 => instruction & 0b100 != 0,
+            _ => instruction & 0b001 != 0,
+        };
+        self.pc = match jump {
+            true => self.a as usize,
+            false => self.pc + 1,
+        };
+        Ok(())
+    }
+
+    fn m(&self) -> Result<i16, String> {
+        match self.a as usize {
+            address if address <= KBD => Ok(self.ram[address]),
+            address => Err(format!(
+                "pc {}: address {} is outside of the ram",
+                self.pc, address
+            )),
+        }
+    }
+
+    fn m_mut(&mut self) -> Result<&mut i16, String> {
+        match self.a as usize {
+            address if address <= KBD => Ok(&mut self.ram[address]),
+            address => Err(format!(
+                "pc {}: address {} is outside of the ram",
+                self.pc, address
+            )),
+        }
+    }
+}
+
+/// the hack alu: `comp` holds the zx, nx, zy, ny, f and no bits.
+fn alu(comp: u16, x: i16, y: i16) -> i16 {
+    let bit = |n: u16| comp & (1 << n) != 0;
+    let x = if bit(5) { 0 } else { x };
+    let x = if bit(4) { !x } else { x };
+    let y = if bit(3) { 0 } else { y };
+    let y = if bit(2) { !y } else { y };
+    let out = if bit(1) { x.wrapping_add(y) } else { x & y };
+    if bit(0) {
+        !out
+    } else {
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Assembler;
+    use std::path::PathBuf;
+
+    fn assemble(asm: &str) -> HackCpu {
+        HackCpu::new(&Assembler::new().process(asm.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_max() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../projects/06/max/Max.asm");
+        let asm = std::fs::read_to_string(path).unwrap();
+        for (x, y, max) in [(3, 7, 7), (-5, -9, -5), (12, 12, 12)] {
+            let mut cpu = assemble(&asm);
+            cpu.poke(0, x);
+            cpu.poke(1, y);
+            assert!(cpu.run(100).unwrap());
+            assert_eq!(cpu.peek(2), max);
+        }
+    }
+
+    #[test]
+    fn test_alu() {
+        let mut cpu = assemble(
+            "@7\nD=A\n@3\nD=D-A\n@16\nM=D\nM=-M\nD=!D\n@17\nM=D|M\nAM=M+1\nD=A-1\n@18\nM=D\n\
+             (END)\n@END\n0;JMP",
+        );
+        assert!(cpu.run(100).unwrap());
+        assert_eq!(&cpu.ram()[16..19], &[-4, -4, -5]);
+        assert_eq!(cpu.cycles(), 14);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut cpu = assemble("@5\nD=A\nD=D<<\n@16\nM=D\nM=M>>\nM=M>>\nA=A<<\nD=A");
+        assert!(cpu.run(100).unwrap());
+        assert_eq!(cpu.peek(16), 2);
+        assert_eq!(cpu.d, 32);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut cpu = assemble("@24577\nM=1");
+        assert!(cpu.run(10).is_err());
+        assert!(HackCpu::new("0101").is_err());
+        assert!(HackCpu::new(&"0000000000000000\n".repeat(ROM_SIZE + 1)).is_err());
+        // a loop that never halts runs out of cycles
+        let mut cpu = assemble("(LOOP)\n@1\nD=A\n@LOOP\n0;JMP");
+        assert!(!cpu.run(1000).unwrap());
+        assert_eq!(cpu.cycles(), 1000);
+    }
+}
@@ -0,0 +1,540 @@
+use crate::vm::{self, Command, Segment};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::PathBuf;
+
+pub const RAM_SIZE: usize = 32768;
+pub const SCREEN: usize = 16384;
+pub const SCREEN_SIZE: usize = 8192;
+pub const KBD: usize = 24576;
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const STATIC_START: usize = 16;
+
+#[derive(Debug, Clone)]
+enum Instr {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    PushStatic(usize),
+    PopStatic(usize),
+    Arithmetic(Command),
+    Goto(usize),
+    IfGoto(usize),
+    IfNotGoto(usize),
+    Function(u16),
+    Call(Option<usize>, u16, String),
+    Return,
+}
+
+/// executes `.vm` programs directly on a hack shaped ram.
+///
+/// like the official vm emulator, execution starts at `Sys.init` when it exists
+/// (without pushing a frame) and at the first command otherwise. use `boot` to
+/// start the way translated programs do.
+pub struct VmEmulator {
+    program: Vec<Instr>,
+    functions: HashMap<String, usize>,
+    ram: Vec<i16>,
+    pc: usize,
+    steps: usize,
+}
+
+impl VmEmulator {
+    /// load a single `.vm` file or every `.vm` file of a directory.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let sources = vm::load_sources(&path)?;
+        Self::from_sources(&vm::as_str_pairs(&sources))
+    }
+
+    /// `sources` are `(file stem, vm code)` pairs, statics are per file stem.
+    pub fn from_sources(sources: &[(&str, &str)]) -> Result<Self, String> {
+        let mut commands = vec![];
+        for (name, source) in sources {
+            let parsed = vm::parse(source).map_err(|e| format!("{}.vm {}", name, e))?;
+            commands.extend(parsed.into_iter().map(|(_, command)| (*name, command)));
+        }
+
+        // first pass: where functions and (function scoped) labels are
+        let mut functions = HashMap::new();
+        let mut labels = HashMap::new();
+        let mut scope = String::new();
+        let mut index = 0;
+        for (name, command) in &commands {
+            match command {
+                Command::Function(function_name, _) => {
+                    scope = function_name.clone();
+                    functions.insert(function_name.clone(), index);
+                }
+                Command::Label(label) => {
+                    labels.insert((name.to_string(), scope.clone(), label.clone()), index);
+                }
+                _ => {}
+            }
+            // labels are not commands of their own, they point at the next one
+            index += match command {
+                Command::Label(_) => 0,
+                Command::Move(..) => 2,
+                _ => 1,
+            };
+        }
+
+        let mut statics: HashMap<(String, u16), usize> = HashMap::new();
+        let mut static_address = |file: &str, i: u16| {
+            let next = STATIC_START + statics.len();
+            *statics.entry((file.to_string(), i)).or_insert(next)
+        };
+        let mut program = Vec::with_capacity(index);
+        let mut scope = String::new();
+        for (name, command) in commands {
+            let resolve = |label: &str| {
+                labels
+                    .get(&(name.to_string(), scope.clone(), label.to_string()))
+                    .copied()
+                    .ok_or(format!("label {} is not defined in {}", label, scope))
+            };
+            let mut push = |segment, i| match segment {
+                Segment::Static => Instr::PushStatic(static_address(name, i)),
+                segment => Instr::Push(segment, i),
+            };
+            let instr = match command {
+                Command::Push(segment, i) => push(segment, i),
+                Command::Pop(Segment::Constant, _) => {
+                    return Err(format!("cannot pop to constant in {}", name))
+                }
+                Command::Pop(Segment::Static, i) => Instr::PopStatic(static_address(name, i)),
+                Command::Pop(segment, i) => Instr::Pop(segment, i),
+                Command::Move(from, i, to, j) => {
+                    program.push(push(from, i));
+                    match to {
+                        Segment::Static => Instr::PopStatic(static_address(name, j)),
+                        to => Instr::Pop(to, j),
+                    }
+                }
+                Command::Label(_) => continue,
+                Command::Goto(label) => Instr::Goto(resolve(&label)?),
+                Command::IfGoto(label) => Instr::IfGoto(resolve(&label)?),
+                Command::IfNotGoto(label) => Instr::IfNotGoto(resolve(&label)?),
+                Command::Function(function_name, n_locals) => {
+                    scope = function_name;
+                    Instr::Function(n_locals)
+                }
+                Command::Call(function_name, n_args) => Instr::Call(
+                    functions.get(&function_name).copied(),
+                    n_args,
+                    function_name,
+                ),
+                Command::Return => Instr::Return,
+                arithmetic => Instr::Arithmetic(arithmetic),
+            };
+            program.push(instr);
+        }
+
+        let pc = functions.get("Sys.init").copied().unwrap_or(0);
+        let mut ram = vec![0; RAM_SIZE];
+        ram[SP] = 256;
+        Ok(VmEmulator {
+            program,
+            functions,
+            ram,
+            pc,
+            steps: 0,
+        })
+    }
+
+    /// start like a translated program: `SP = 256` then `call Sys.init 0`.
+    pub fn boot(&mut self) -> Result<&mut Self, String> {
+        self.ram[SP] = 256;
+        self.pc = self.program.len();
+        self.call(self.functions.get("Sys.init").copied(), 0, "Sys.init")?;
+        Ok(self)
+    }
+
+    pub fn ram(&self) -> &[i16] {
+        &self.ram
+    }
+
+    pub fn peek(&self, address: usize) -> i16 {
+        self.ram[address]
+    }
+
+    pub fn poke(&mut self, address: usize, value: i16) {
+        self.ram[address] = value
+    }
+
+    pub fn screen(&self) -> &[i16] {
+        &self.ram[SCREEN..SCREEN + SCREEN_SIZE]
+    }
+
+    /// simulate the key currently held down, 0 for none.
+    pub fn set_key(&mut self, key: i16) {
+        self.ram[KBD] = key
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// run at most `steps` commands, returns how many actually ran.
+    pub fn run(&mut self, steps: usize) -> Result<usize, String> {
+        let start = self.steps;
+        while self.steps - start < steps && self.step()? {}
+        Ok(self.steps - start)
+    }
+
+    /// execute one command, returns false once the program ran off its end.
+    pub fn step(&mut self) -> Result<bool, String> {
+        let instr = match self.program.get(self.pc) {
+            Some(instr) => instr.clone(),
+            None => return Ok(false),
+        };
+        self.pc += 1;
+        self.steps += 1;
+        match instr {
+            Instr::Push(Segment::Constant, i) => self.push(i as i16)?,
+            Instr::Push(segment, i) => {
+                let address = self.address(segment, i)?;
+                self.push(self.ram[address])?
+            }
+            Instr::Pop(segment, i) => {
+                let address = self.address(segment, i)?;
+                self.ram[address] = self.pop()?;
+            }
+            Instr::PushStatic(address) => self.push(self.ram[address])?,
+            Instr::PopStatic(address) => self.ram[address] = self.pop()?,
+            Instr::Arithmetic(command) => self.arithmetic(&command)?,
+            Instr::Goto(target) => self.pc = target,
+            Instr::IfGoto(target) => {
+                if self.pop()? != 0 {
+                    self.pc = target
+                }
+            }
+            Instr::IfNotGoto(target) => {
+                if self.pop()? != -1 {
+                    self.pc = target
+                }
+            }
+            Instr::Function(n_locals) => {
+                for _ in 0..n_locals {
+                    self.push(0)?
+                }
+            }
+            Instr::Call(target, n_args, name) => self.call(target, n_args, &name)?,
+            Instr::Return => {
+                let frame = self.ram[LCL] as u16 as usize;
+                let return_address = self.at(frame.wrapping_sub(5))?;
+                let value = self.pop()?;
+                let arg = self.ram[ARG] as u16 as usize;
+                *self.at_mut(arg)? = value;
+                self.ram[SP] = (arg + 1) as i16;
+                self.ram[THAT] = self.at(frame.wrapping_sub(1))?;
+                self.ram[THIS] = self.at(frame.wrapping_sub(2))?;
+                self.ram[ARG] = self.at(frame.wrapping_sub(3))?;
+                self.ram[LCL] = self.at(frame.wrapping_sub(4))?;
+                self.pc = return_address as u16 as usize;
+            }
+        }
+        Ok(true)
+    }
+
+    /// `RAM[i] = v` for every non zero word in `range`.
+    pub fn dump_ram(&self, range: Range<usize>) -> String {
+        self.ram[range.clone()]
+            .iter()
+            .zip(range)
+            .filter(|(value, _)| **value != 0)
+            .map(|(value, address)| format!("RAM[{}] = {}\n", address, value))
+            .collect()
+    }
+
+    fn call(&mut self, target: Option<usize>, n_args: u16, name: &str) -> Result<(), String> {
+        let target = target.ok_or(format!("function {} is not defined", name))?;
+        self.push(self.pc as i16)?;
+        for pointer in &[LCL, ARG, THIS, THAT] {
+            self.push(self.ram[*pointer])?;
+        }
+        let sp = self.ram[SP];
+        // the arguments were pushed before the frame
+        let arg = sp as i32 - 5 - n_args as i32;
+        if arg < 0 {
+            return Err("stack underflow".to_string());
+        }
+        self.ram[ARG] = arg as i16;
+        self.ram[LCL] = sp;
+        self.pc = target;
+        Ok(())
+    }
+
+    fn arithmetic(&mut self, command: &Command) -> Result<(), String> {
+        let y = self.pop()?;
+        let result = match command {
+            Command::Neg => y.wrapping_neg(),
+            Command::Not => !y,
+            Command::Inc => y.wrapping_add(1),
+            Command::Shl => y.wrapping_shl(1),
+            Command::Shr => y >> 1,
+            _ => {
+                let x = self.pop()?;
+                match command {
+                    Command::Add => x.wrapping_add(y),
+                    Command::Sub => x.wrapping_sub(y),
+                    Command::And => x & y,
+                    Command::Or => x | y,
+                    Command::Eq => -((x == y) as i16),
+                    Command::Gt => -((x > y) as i16),
+                    Command::Lt => -((x < y) as i16),
+                    Command::Mul => x.wrapping_mul(y),
+                    Command::Div => x.checked_div(y).unwrap_or(if y == 0 { 0 } else { x }),
+                    _ => return Err(format!("{} is not an arithmetic command", command)),
+                }
+            }
+        };
+        self.push(result)
+    }
+
+    fn address(&self, segment: Segment, i: u16) -> Result<usize, String> {
+        let i = i as usize;
+        let address = match segment {
+            Segment::Local => self.ram[LCL] as u16 as usize + i,
+            Segment::Argument => self.ram[ARG] as u16 as usize + i,
+            Segment::This => self.ram[THIS] as u16 as usize + i,
+            Segment::That => self.ram[THAT] as u16 as usize + i,
+            Segment::Pointer if i < 2 => THIS + i,
+            Segment::Temp if i < 8 => 5 + i,
+            _ => return Err(format!("{} {} is out of range", segment.as_str(), i)),
+        };
+        if address >= RAM_SIZE {
+            return Err(format!("address {} is out of range", address));
+        }
+        Ok(address)
+    }
+
+    fn at(&self, address: usize) -> Result<i16, String> {
+        self.ram
+            .get(address)
+            .copied()
+            .ok_or(format!("address {} is out of range", address))
+    }
+
+    fn at_mut(&mut self, address: usize) -> Result<&mut i16, String> {
+        self.ram
+            .get_mut(address)
+            .ok_or(format!("address {} is out of range", address))
+    }
+
+    fn push(&mut self, value: i16) -> Result<(), String> {
+        let sp = self.ram[SP];
+        let next = sp.checked_add(1).ok_or("stack overflow")?;
+        *self.at_mut(sp as u16 as usize)? = value;
+        self.ram[SP] = next;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i16, String> {
+        let sp = match self.ram[SP] {
+            sp if sp > 0 => sp - 1,
+            _ => return Err("stack underflow".to_string()),
+        };
+        self.ram[SP] = sp;
+        self.at(sp as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    /// run a `*VME.tst` script natively and compare against its `.cmp` file
+    fn run_script(name: &str) {
+        let mut vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../projects/");
+        vm_path.push(OsStr::new(name));
+        let filename = vm_path.file_name().unwrap().to_string_lossy().to_string();
+        let script = std::fs::read_to_string(vm_path.join(format!("{}VME.tst", filename)))
+            .expect("failed to read test script");
+        let cmp = std::fs::read_to_string(vm_path.join(format!("{}.cmp", filename)))
+            .expect("failed to read compare file");
+
+        let script = script
+            .lines()
+            .map(|line| line.split("//").next().unwrap())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let mut emulator = None;
+        let mut output_list = vec![];
+        let mut actual: Vec<i16> = vec![];
+        for statement in script
+            .split([',', ';'])
+            .map(|s| s.trim().trim_start_matches('}').trim())
+        {
+            let parts: Vec<&str> = statement.split_whitespace().collect();
+            match parts.as_slice() {
+                ["load"] => emulator = Some(VmEmulator::load(vm_path.clone()).unwrap()),
+                ["load", file] => emulator = Some(VmEmulator::load(vm_path.join(file)).unwrap()),
+                ["output-list", list @ ..] => {
+                    output_list = list
+                        .iter()
+                        .map(|item| item[4..item.find(']').unwrap()].parse().unwrap())
+                        .collect()
+                }
+                ["set", target, value] => {
+                    let emulator = emulator.as_mut().unwrap();
+                    let (name, offset) = match target.find('[') {
+                        Some(i) => (
+                            &target[..i],
+                            Some(target[i + 1..target.len() - 1].parse().unwrap()),
+                        ),
+                        None => (*target, None),
+                    };
+                    let address = match (name, offset) {
+                        ("sp", None) => SP,
+                        ("local", None) => LCL,
+                        ("argument", None) => ARG,
+                        ("this", None) => THIS,
+                        ("that", None) => THAT,
+                        ("local", Some(i)) => emulator.peek(LCL) as usize + i,
+                        ("argument", Some(i)) => emulator.peek(ARG) as usize + i,
+                        (_, Some(i)) => i,
+                        _ => panic!("cannot set {}", target),
+                    };
+                    emulator.poke(address, value.parse().unwrap());
+                }
+                ["repeat", steps, "{", "vmstep"] => {
+                    emulator
+                        .as_mut()
+                        .unwrap()
+                        .run(steps.parse().unwrap())
+                        .unwrap();
+                }
+                ["output"] => {
+                    let emulator = emulator.as_ref().unwrap();
+                    actual.extend(output_list.iter().map(|address| emulator.peek(*address)));
+                }
+                _ => {}
+            }
+        }
+
+        let expected: Vec<i16> = cmp
+            .lines()
+            .skip(1)
+            .step_by(2)
+            .flat_map(|line| line.split('|'))
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(|value| value.parse().unwrap())
+            .collect();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_simple_add() {
+        run_script("07/StackArithmetic/SimpleAdd")
+    }
+    #[test]
+    fn test_stack_test() {
+        run_script("07/StackArithmetic/StackTest")
+    }
+    #[test]
+    fn test_basic_test() {
+        run_script("07/MemoryAccess/BasicTest")
+    }
+    #[test]
+    fn test_pointer_test() {
+        run_script("07/MemoryAccess/PointerTest")
+    }
+    #[test]
+    fn test_static_test() {
+        run_script("07/MemoryAccess/StaticTest")
+    }
+    #[test]
+    fn test_basic_loop() {
+        run_script("08/ProgramFlow/BasicLoop")
+    }
+    #[test]
+    fn test_fibonacci_series() {
+        run_script("08/ProgramFlow/FibonacciSeries")
+    }
+    #[test]
+    fn test_simple_function() {
+        run_script("08/FunctionCalls/SimpleFunction")
+    }
+    #[test]
+    fn test_nested_call() {
+        run_script("08/FunctionCalls/NestedCall")
+    }
+    #[test]
+    fn test_fibonacci_element() {
+        run_script("08/FunctionCalls/FibonacciElement")
+    }
+    #[test]
+    fn test_statics_test() {
+        run_script("08/FunctionCalls/StaticsTest")
+    }
+
+    #[test]
+    fn test_boot_and_keyboard() {
+        let mut emulator = VmEmulator::from_sources(&[(
+            "Sys",
+            "function Sys.init 0\n\
+             push constant 24576\n\
+             pop pointer 1\n\
+             push that 0\n\
+             pop static 0\n\
+             label END\n\
+             goto END",
+        )])
+        .unwrap();
+        emulator.boot().unwrap().set_key(65);
+        emulator.run(100).unwrap();
+        assert_eq!(emulator.peek(STATIC_START), 65);
+        assert_eq!(emulator.peek(SP), 261);
+        assert_eq!(emulator.steps(), 100);
+        assert_eq!(emulator.dump_ram(16..17), "RAM[16] = 65\n");
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        let mut emulator = VmEmulator::from_sources(&[("Sys", crate::vm::EXTENDED_SYS)]).unwrap();
+        emulator.run(100).unwrap();
+        let statics = (STATIC_START..STATIC_START + 5)
+            .map(|address| emulator.peek(address))
+            .collect::<Vec<i16>>();
+        assert_eq!(statics, crate::vm::EXTENDED_STATICS);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(VmEmulator::from_sources(&[("Main", "goto NOWHERE")]).is_err());
+        let mut emulator = VmEmulator::from_sources(&[("Main", "call Math.multiply 2")]).unwrap();
+        assert!(emulator.run(1).is_err());
+        let mut emulator = VmEmulator::from_sources(&[("Main", "push constant 1")]).unwrap();
+        assert_eq!(emulator.run(10), Ok(1));
+        assert!(emulator.is_halted());
+    }
+
+    #[test]
+    fn test_stack_overflow_and_underflow() {
+        let recursion = "function Sys.init 0\ncall Main.f 0\nreturn\n\
+                         function Main.f 0\npush constant 1\ncall Main.f 0\nreturn";
+        let mut emulator = VmEmulator::from_sources(&[("Sys", recursion)]).unwrap();
+        emulator.boot().unwrap();
+        assert_eq!(emulator.run(100_000), Err("stack overflow".to_string()));
+        assert_eq!(emulator.peek(0), 32767);
+
+        let mut emulator = VmEmulator::from_sources(&[("Main", "pop temp 0")]).unwrap();
+        emulator.poke(0, 0);
+        assert_eq!(emulator.run(1), Err("stack underflow".to_string()));
+        // more arguments than the stack holds
+        let call = "call Main.f 32763
+function Main.f 0
+return";
+        let mut emulator = VmEmulator::from_sources(&[("Main", call)]).unwrap();
+        assert_eq!(emulator.run(1), Err("stack underflow".to_string()));
+    }
+}
@@ -0,0 +1,425 @@
+use crate::stack_depth::STACK_START;
+use crate::vm::{Command, Segment};
+use std::collections::{HashMap, HashSet};
+
+/// words of ram 16..255 the assembler gives to statics, one per file and index.
+const STATIC_WORDS: usize = STACK_START - 16;
+
+/// a function simple enough to paste into its callers.
+struct Candidate {
+    file: String,
+    n_locals: u16,
+    /// highest argument index used plus one, call sites must pass at least that many
+    n_args: u16,
+    body: Vec<Command>,
+    uses_statics: bool,
+    writes_pointer: [bool; 2],
+}
+
+/// replace `call f n` by the body of `f` when `f` has at most `max_size` commands,
+/// calls nothing and leaves exactly its return value on the stack at every
+/// `return`. arguments and locals move to fresh `static` slots of the caller's
+/// file, `return` becomes a jump past the pasted body and THIS / THAT are saved
+/// and restored around it when `f` changes them. functions using `static` are
+/// only inlined into their own file. a call stays a call once its slots would push
+/// the statics of the whole program past RAM[255]. returns the number of inlined
+/// call sites.
+pub fn inline(files: &mut [(String, Vec<(usize, Command)>)], max_size: usize) -> usize {
+    let mut candidates = HashMap::new();
+    for (file, commands) in files.iter() {
+        for (index, (_, command)) in commands.iter().enumerate() {
+            if let Command::Function(name, n_locals) = command {
+                let body = commands[index + 1..]
+                    .iter()
+                    .map(|(_, command)| command)
+                    .take_while(|command| !matches!(command, Command::Function(..)))
+                    .cloned()
+                    .collect::<Vec<Command>>();
+                if let Some(candidate) = candidate(file, *n_locals, body, max_size) {
+                    candidates.insert(name.clone(), candidate);
+                }
+            }
+        }
+    }
+
+    let statics = files
+        .iter()
+        .map(|(_, commands)| static_indices(commands))
+        .collect::<Vec<HashSet<u16>>>();
+    let mut free = STATIC_WORDS.saturating_sub(statics.iter().map(HashSet::len).sum());
+    let mut inlined = 0;
+    for ((file, commands), statics) in files.iter_mut().zip(&statics) {
+        // fresh slots start after every static the file already uses
+        let base = statics.iter().map(|i| i + 1).max().unwrap_or(0);
+        let mut top = base;
+        let mut output = Vec::with_capacity(commands.len());
+        for (line, command) in commands.drain(..) {
+            let candidate = match &command {
+                Command::Call(name, n_args) => candidates
+                    .get(name)
+                    .filter(|c| c.n_args <= *n_args && (!c.uses_statics || c.file == *file))
+                    .map(|c| (name.clone(), *n_args, c))
+                    .filter(|(_, n_args, c)| {
+                        let needed = (base + slots(c, *n_args)).saturating_sub(top) as usize;
+                        needed <= free
+                    }),
+                _ => None,
+            };
+            match candidate {
+                Some((name, n_args, candidate)) => {
+                    let site_top = base + slots(candidate, n_args);
+                    free -= site_top.saturating_sub(top) as usize;
+                    top = top.max(site_top);
+                    let site = format!("{}$inline.{}", name, inlined);
+                    for command in expand(candidate, &site, n_args, base) {
+                        output.push((line, command));
+                    }
+                    inlined += 1;
+                }
+                None => output.push((line, command)),
+            }
+        }
+        *commands = output;
+    }
+    inlined
+}
+
+/// the static indices `commands` use.
+fn static_indices(commands: &[(usize, Command)]) -> HashSet<u16> {
+    commands
+        .iter()
+        .flat_map(|(_, command)| match command {
+            Command::Push(Segment::Static, i) | Command::Pop(Segment::Static, i) => vec![*i],
+            Command::Move(from, i, to, j) => [(*from, *i), (*to, *j)]
+                .iter()
+                .filter(|(segment, _)| *segment == Segment::Static)
+                .map(|(_, i)| *i)
+                .collect(),
+            _ => vec![],
+        })
+        .collect()
+}
+
+/// static slots one call site with `n_args` arguments takes, see `expand`.
+fn slots(candidate: &Candidate, n_args: u16) -> u16 {
+    let saved = match candidate.writes_pointer {
+        [_, true] => 2,
+        [true, false] => 1,
+        [false, false] => 0,
+    };
+    n_args + candidate.n_locals + saved
+}
+
+fn candidate(file: &str, n_locals: u16, body: Vec<Command>, max_size: usize) -> Option<Candidate> {
+    if body.len() > max_size || body.last() != Some(&Command::Return) {
+        return None;
+    }
+    let mut n_args = 0;
+    let mut uses_statics = false;
+    let mut writes_pointer = [false; 2];
+    for command in &body {
+        let accesses = match command {
+            Command::Call(..) => return None,
+            Command::Push(segment, i) => vec![(*segment, *i, false)],
+            Command::Pop(segment, i) => vec![(*segment, *i, true)],
+            Command::Move(from, i, to, j) => vec![(*from, *i, false), (*to, *j, true)],
+            _ => vec![],
+        };
+        for (segment, i, write) in accesses {
+            match segment {
+                Segment::Argument => n_args = n_args.max(i + 1),
+                Segment::Local if i >= n_locals => return None,
+                Segment::Static => uses_statics = true,
+                Segment::Pointer if write => writes_pointer[i as usize & 1] = true,
+                _ => {}
+            }
+        }
+    }
+    if !returns_one_value(&body) {
+        return None;
+    }
+    Some(Candidate {
+        file: file.to_string(),
+        n_locals,
+        n_args,
+        body,
+        uses_statics,
+        writes_pointer,
+    })
+}
+
+/// true when every path through `body` keeps its own operands and reaches
+/// `return` with exactly one value on the stack.
+fn returns_one_value(body: &[Command]) -> bool {
+    let labels = body
+        .iter()
+        .enumerate()
+        .filter_map(|(index, command)| match command {
+            Command::Label(label) => Some((label.as_str(), index)),
+            _ => None,
+        })
+        .collect::<HashMap<&str, usize>>();
+    let mut seen = HashMap::new();
+    let mut pending = vec![(0, 0)];
+    while let Some((index, depth)) = pending.pop() {
+        let Some(command) = body.get(index) else {
+            return false;
+        };
+        if let Some(previous) = seen.insert(index, depth) {
+            if previous != depth {
+                return false;
+            }
+            continue;
+        }
+        let (pops, pushes) = match command {
+            Command::Push(..) => (0, 1),
+            Command::Pop(..) | Command::IfGoto(_) | Command::IfNotGoto(_) => (1, 0),
+            Command::Neg | Command::Not | Command::Inc | Command::Shl | Command::Shr => (1, 1),
+            Command::Add
+            | Command::Mul
+            | Command::Div
+            | Command::Sub
+            | Command::Eq
+            | Command::Gt
+            | Command::Lt
+            | Command::And
+            | Command::Or => (2, 1),
+            _ => (0, 0),
+        };
+        if depth < pops {
+            return false;
+        }
+        let after = depth - pops + pushes;
+        let target = |label: &String| labels.get(label.as_str()).copied();
+        match command {
+            Command::Return if depth != 1 => return false,
+            Command::Return => {}
+            Command::Goto(label) => match target(label) {
+                Some(target) => pending.push((target, after)),
+                None => return false,
+            },
+            Command::IfGoto(label) | Command::IfNotGoto(label) => match target(label) {
+                Some(target) => {
+                    pending.push((target, after));
+                    pending.push((index + 1, after));
+                }
+                None => return false,
+            },
+            _ => pending.push((index + 1, after)),
+        }
+    }
+    true
+}
+
+/// the commands replacing one `call`, `site` makes its labels unique.
+fn expand(candidate: &Candidate, site: &str, n_args: u16, base: u16) -> Vec<Command> {
+    let argument = |i: u16| base + i;
+    let local = |i: u16| base + n_args + i;
+    let saved = |i: u16| base + n_args + candidate.n_locals + i;
+    let label = |name: &str| format!("{}.{}", site, name);
+    let end = format!("{}$end", site);
+
+    let mut commands = vec![];
+    for i in (0..n_args).rev() {
+        commands.push(Command::Pop(Segment::Static, argument(i)));
+    }
+    for i in 0..2 {
+        if candidate.writes_pointer[i as usize] {
+            commands.push(Command::Push(Segment::Pointer, i));
+            commands.push(Command::Pop(Segment::Static, saved(i)));
+        }
+    }
+    for i in 0..candidate.n_locals {
+        commands.push(Command::Push(Segment::Constant, 0));
+        commands.push(Command::Pop(Segment::Static, local(i)));
+    }
+
+    let rename = |segment: Segment, i: u16| match segment {
+        Segment::Argument => (Segment::Static, argument(i)),
+        Segment::Local => (Segment::Static, local(i)),
+        segment => (segment, i),
+    };
+    let last = candidate.body.len() - 1;
+    let mut jumps_to_end = false;
+    for (index, command) in candidate.body.iter().enumerate() {
+        commands.push(match command {
+            Command::Push(segment, i) => {
+                let (segment, i) = rename(*segment, *i);
+                Command::Push(segment, i)
+            }
+            Command::Pop(segment, i) => {
+                let (segment, i) = rename(*segment, *i);
+                Command::Pop(segment, i)
+            }
+            Command::Move(from, i, to, j) => {
+                let (from, i) = rename(*from, *i);
+                let (to, j) = rename(*to, *j);
+                Command::Move(from, i, to, j)
+            }
+            Command::Label(name) => Command::Label(label(name)),
+            Command::Goto(name) => Command::Goto(label(name)),
+            Command::IfGoto(name) => Command::IfGoto(label(name)),
+            Command::IfNotGoto(name) => Command::IfNotGoto(label(name)),
+            // the last return simply falls through
+            Command::Return if index == last => continue,
+            Command::Return => {
+                jumps_to_end = true;
+                Command::Goto(end.clone())
+            }
+            command => command.clone(),
+        });
+    }
+    if jumps_to_end {
+        commands.push(Command::Label(end));
+    }
+    for i in 0..2 {
+        if candidate.writes_pointer[i as usize] {
+            commands.push(Command::Push(Segment::Static, saved(i)));
+            commands.push(Command::Pop(Segment::Pointer, i));
+        }
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm;
+
+    fn inline_source(sources: &[(&str, &str)], max_size: usize) -> (usize, Vec<String>) {
+        let mut files = sources
+            .iter()
+            .map(|(name, source)| (name.to_string(), vm::parse(source).unwrap()))
+            .collect::<Vec<_>>();
+        let inlined = inline(&mut files, max_size);
+        let lines = files[0].1.iter().map(|(_, c)| c.to_string()).collect();
+        (inlined, lines)
+    }
+
+    const SQUARE: &str = "function Square.getX 0\n\
+                          push argument 0\n\
+                          pop pointer 0\n\
+                          push this 0\n\
+                          return";
+
+    #[test]
+    fn test_getter() {
+        let main = "function Main.main 1\npush local 0\ncall Square.getX 1\nreturn";
+        let (inlined, lines) = inline_source(&[("Main", main), ("Square", SQUARE)], 8);
+        assert_eq!(inlined, 1);
+        assert_eq!(
+            lines,
+            vec![
+                "function Main.main 1",
+                "push local 0",
+                "pop static 0",
+                "push pointer 0",
+                "pop static 1",
+                "push static 0",
+                "pop pointer 0",
+                "push this 0",
+                "push static 1",
+                "pop pointer 0",
+                "return",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_locals_labels_and_returns() {
+        let main = "push static 3\n\
+                    function Main.abs 1\n\
+                    push argument 0\n\
+                    pop local 0\n\
+                    push local 0\n\
+                    push constant 0\n\
+                    lt\n\
+                    if-goto NEGATIVE\n\
+                    push local 0\n\
+                    return\n\
+                    label NEGATIVE\n\
+                    push local 0\n\
+                    neg\n\
+                    return\n\
+                    function Main.main 0\n\
+                    push constant 5\n\
+                    call Main.abs 1\n\
+                    return";
+        let (inlined, lines) = inline_source(&[("Main", main)], 12);
+        assert_eq!(inlined, 1);
+        let start = lines
+            .iter()
+            .position(|l| l == "function Main.main 0")
+            .unwrap();
+        assert_eq!(
+            lines[start..],
+            [
+                "function Main.main 0",
+                "push constant 5",
+                "pop static 4",
+                "push constant 0",
+                "pop static 5",
+                "push static 4",
+                "pop static 5",
+                "push static 5",
+                "push constant 0",
+                "lt",
+                "if-goto Main.abs$inline.0.NEGATIVE",
+                "push static 5",
+                "goto Main.abs$inline.0$end",
+                "label Main.abs$inline.0.NEGATIVE",
+                "push static 5",
+                "neg",
+                "label Main.abs$inline.0$end",
+                "return",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_not_inlined() {
+        let cases = [
+            // too big
+            "function Main.f 0\npush constant 1\npush constant 2\nadd\nreturn",
+            // calls another function
+            "function Main.f 0\ncall Output.println 0\nreturn",
+            // uses the caller's stack
+            "function Main.f 0\nadd\nreturn",
+            // leaves more than the return value
+            "function Main.f 0\npush constant 1\npush constant 2\nreturn",
+        ];
+        for case in &cases {
+            let source = format!("{}\nfunction Main.main 0\ncall Main.f 0\nreturn", case);
+            assert_eq!(inline_source(&[("Main", &source)], 3).0, 0, "{}", case);
+        }
+        // statics belong to the callee's file, argument 1 is not passed
+        let counter = "function Counter.next 0\npush static 0\nreturn";
+        let main = "function Main.main 0\ncall Counter.next 0\nreturn";
+        assert_eq!(
+            inline_source(&[("Main", main), ("Counter", counter)], 8).0,
+            0
+        );
+        let main = "function Main.main 0\npush constant 1\ncall Square.getX 0\nreturn";
+        assert_eq!(inline_source(&[("Main", main), ("Square", SQUARE)], 8).0, 0);
+    }
+
+    #[test]
+    fn test_statics_stay_below_the_stack() {
+        // the getter takes an argument slot and a slot saving pointer 0
+        let main = |statics: u16| {
+            let uses = (0..statics)
+                .map(|i| format!("push static {}\npop temp 0\n", i))
+                .collect::<String>();
+            format!(
+                "function Main.main 0\n{}push constant 1\ncall Square.getX 1\n\
+                 push constant 1\ncall Square.getX 1\nreturn",
+                uses
+            )
+        };
+        // Square has a static of its own, two sites share the same slots
+        let square = format!("{}\nfunction Square.get 0\npush static 0\nreturn", SQUARE);
+        let count = |statics| inline_source(&[("Main", &main(statics)), ("Square", &square)], 8).0;
+        assert_eq!(count(237), 2);
+        assert_eq!(count(238), 0);
+    }
+}
@@ -1,9 +1,24 @@
 pub mod assembler;
+pub mod callgraph;
+pub mod cpu;
+pub mod emulator;
+pub mod inliner;
+pub mod lint;
+pub mod optimizer;
 pub mod parser;
+pub mod profile;
+pub mod source_map;
+pub mod stack_depth;
 pub mod tokenizer;
 pub mod translator;
+pub mod vm;
 
 pub use assembler::*;
+pub use callgraph::CallGraph;
+pub use cpu::HackCpu;
+pub use emulator::VmEmulator;
 pub use parser::*;
+pub use source_map::SourceMap;
 pub use tokenizer::*;
 pub use translator::*;
+pub use vm::{Command, Segment};
@@ -0,0 +1,245 @@
+use crate::callgraph::CallGraph;
+use crate::vm::{Command, Segment};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// a mistake found by `lint`, the translators accept such code silently.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.vm:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// check `(file stem, commands)` pairs as returned by `vm::parse` for calls to
+/// undefined functions, argument counts that differ between call sites, jumps to
+/// labels missing in the function, locals beyond the declared count, functions
+/// without `return` and unreachable commands. warnings are sorted by file and line.
+pub fn lint(files: &[(&str, &[(usize, Command)])]) -> Vec<Warning> {
+    let mut warnings = vec![];
+    let mut warn = |file: &str, line: usize, message: String| {
+        warnings.push(Warning {
+            file: file.to_string(),
+            line,
+            message,
+        })
+    };
+
+    let graph = CallGraph::new(files);
+    let mut first_calls = BTreeMap::new();
+    for call in &graph.calls {
+        if !graph.functions.contains_key(&call.callee) {
+            warn(
+                &call.file,
+                call.line,
+                format!("function {} is not defined", call.callee),
+            );
+        }
+        let first = first_calls.entry(&call.callee).or_insert(call);
+        if first.n_args != call.n_args {
+            warn(
+                &call.file,
+                call.line,
+                format!(
+                    "{} is called with {} arguments here but with {} at {}.vm:{}",
+                    call.callee, call.n_args, first.n_args, first.file, first.line
+                ),
+            );
+        }
+    }
+
+    for (file, commands) in files {
+        for body in split_functions(commands) {
+            lint_body(&body, &mut |line, message| warn(file, line, message));
+        }
+    }
+
+    warnings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    warnings
+}
+
+/// commands of one function, or of the code before the first function of a file.
+struct Body<'a> {
+    /// name and local count, `None` for the code outside of functions
+    function: Option<(&'a str, u16)>,
+    line: usize,
+    commands: &'a [(usize, Command)],
+}
+
+fn split_functions(commands: &[(usize, Command)]) -> Vec<Body<'_>> {
+    let mut bodies = vec![];
+    let mut start = 0;
+    let mut function = None;
+    let mut line = commands.first().map_or(0, |(line, _)| *line);
+    for (index, (number, command)) in commands.iter().enumerate() {
+        if let Command::Function(name, n_locals) = command {
+            if index > start || function.is_some() {
+                bodies.push(Body {
+                    function,
+                    line,
+                    commands: &commands[start..index],
+                });
+            }
+            function = Some((name.as_str(), *n_locals));
+            line = *number;
+            start = index + 1;
+        }
+    }
+    if start < commands.len() || function.is_some() {
+        bodies.push(Body {
+            function,
+            line,
+            commands: &commands[start..],
+        });
+    }
+    bodies
+}
+
+fn lint_body(body: &Body, warn: &mut impl FnMut(usize, String)) {
+    let labels = body
+        .commands
+        .iter()
+        .filter_map(|(_, command)| match command {
+            Command::Label(label) => Some(label.as_str()),
+            _ => None,
+        })
+        .collect::<HashSet<&str>>();
+    let scope = match body.function {
+        Some((name, _)) => name.to_string(),
+        None => "the code outside of functions".to_string(),
+    };
+
+    let mut reachable = true;
+    for (line, command) in body.commands {
+        match command {
+            Command::Label(_) => reachable = true,
+            _ if !reachable => {
+                warn(*line, format!("`{}` is unreachable", command));
+                // one warning for a whole run of dead code
+                reachable = true;
+            }
+            _ => {}
+        }
+        match command {
+            Command::Goto(label) | Command::IfGoto(label) | Command::IfNotGoto(label)
+                if !labels.contains(label.as_str()) =>
+            {
+                warn(
+                    *line,
+                    format!("label {} is not defined in {}", label, scope),
+                );
+            }
+            Command::Push(Segment::Local, i) | Command::Pop(Segment::Local, i) => {
+                if let Some((name, n_locals)) = body.function {
+                    if *i >= n_locals {
+                        warn(
+                            *line,
+                            format!(
+                                "local {} is out of range, {} has {} locals",
+                                i, name, n_locals
+                            ),
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Command::Goto(_) | Command::Return = command {
+            reachable = false;
+        }
+    }
+
+    // the bootstrap code calls `Sys.init` without a frame to return to
+    if let Some((name, _)) = body.function.filter(|(name, _)| *name != "Sys.init") {
+        if !body.commands.iter().any(|(_, c)| *c == Command::Return) {
+            warn(body.line, format!("function {} never returns", name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm;
+
+    fn messages(sources: &[(&str, &str)]) -> Vec<String> {
+        let files = sources
+            .iter()
+            .map(|(name, source)| (*name, vm::parse(source).unwrap()))
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(name, commands)| (*name, commands.as_slice()))
+            .collect::<Vec<_>>();
+        lint(&files).iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn test_clean_projects() {
+        for name in &[
+            "08/FunctionCalls/FibonacciElement",
+            "08/FunctionCalls/NestedCall",
+            "08/FunctionCalls/StaticsTest",
+            "08/ProgramFlow/FibonacciSeries",
+        ] {
+            let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("../../projects")
+                .join(name);
+            let sources = vm::load_sources(&path).unwrap();
+            assert_eq!(messages(&vm::as_str_pairs(&sources)), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn test_warnings() {
+        let main = "function Main.main 1\n\
+                    push local 1\n\
+                    call Main.f 1\n\
+                    call Main.f 2\n\
+                    call Foo.bar 0\n\
+                    goto END\n\
+                    push constant 1\n\
+                    pop local 0\n\
+                    label END\n\
+                    return\n\
+                    push constant 2\n\
+                    function Main.f 0\n\
+                    goto NOWHERE\n\
+                    function Main.g 0\n\
+                    label LOOP\n\
+                    goto LOOP";
+        assert_eq!(
+            messages(&[("Main", main)]),
+            vec![
+                "Main.vm:2: local 1 is out of range, Main.main has 1 locals",
+                "Main.vm:4: Main.f is called with 2 arguments here but with 1 at Main.vm:3",
+                "Main.vm:5: function Foo.bar is not defined",
+                "Main.vm:7: `push constant 1` is unreachable",
+                "Main.vm:11: `push constant 2` is unreachable",
+                "Main.vm:12: function Main.f never returns",
+                "Main.vm:13: label NOWHERE is not defined in Main.f",
+                "Main.vm:14: function Main.g never returns",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_are_function_scoped() {
+        let main = "function Main.a 0\n\
+                    label LOOP\n\
+                    return\n\
+                    function Main.b 0\n\
+                    if-goto LOOP\n\
+                    return";
+        assert_eq!(
+            messages(&[("Main", main)]),
+            vec!["Main.vm:5: label LOOP is not defined in Main.b"]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use crate::vm::{Command, Segment};
+
+/// vm-to-vm peephole pass run before code generation.
+///
+/// - `push constant 1` / `add` becomes `inc`
+/// - `push X` / `pop Y` becomes `move X Y`
+/// - `not` / `if-goto L` becomes `if-not-goto L`
+/// - `goto L` directly followed by `label L` is dropped
+///
+/// fused commands keep the line number of their first command.
+pub fn peephole(commands: Vec<(usize, Command)>) -> Vec<(usize, Command)> {
+    let mut out = Vec::with_capacity(commands.len());
+    let mut commands = commands.into_iter().peekable();
+    while let Some((line, command)) = commands.next() {
+        let fused = match (&command, commands.peek().map(|(_, next)| next)) {
+            (Command::Push(Segment::Constant, 1), Some(Command::Add)) => Some(Command::Inc),
+            (Command::Push(from, i), Some(Command::Pop(to, j))) => {
+                Some(Command::Move(*from, *i, *to, *j))
+            }
+            (Command::Not, Some(Command::IfGoto(label))) => Some(Command::IfNotGoto(label.clone())),
+            (Command::Goto(target), Some(Command::Label(label))) if target == label => {
+                // falls through to the label anyway
+                continue;
+            }
+            _ => None,
+        };
+        match fused {
+            Some(fused) => {
+                commands.next();
+                out.push((line, fused));
+            }
+            None => out.push((line, command)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::parse;
+
+    fn optimize(source: &str) -> Vec<String> {
+        peephole(parse(source).unwrap())
+            .iter()
+            .map(|(_, command)| command.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_inc() {
+        assert_eq!(
+            optimize("push local 0\npush constant 1\nadd"),
+            vec!["push local 0", "inc"]
+        );
+        assert_eq!(
+            optimize("push constant 2\nadd"),
+            vec!["push constant 2", "add"]
+        );
+    }
+
+    #[test]
+    fn test_move() {
+        assert_eq!(
+            optimize("push argument 1\npop static 0\npush constant 3\npop local 2"),
+            vec!["move argument 1 static 0", "move constant 3 local 2"]
+        );
+    }
+
+    #[test]
+    fn test_inverted_jump() {
+        assert_eq!(
+            optimize("lt\nnot\nif-goto END"),
+            vec!["lt", "if-not-goto END"]
+        );
+    }
+
+    #[test]
+    fn test_redundant_goto() {
+        assert_eq!(
+            optimize("goto NEXT\nlabel NEXT\ngoto LOOP\nlabel OTHER"),
+            vec!["label NEXT", "goto LOOP", "label OTHER"]
+        );
+    }
+
+    #[test]
+    fn test_keeps_line_numbers() {
+        let commands = peephole(parse("push local 0\n\npush constant 1\nadd\nreturn").unwrap());
+        let lines: Vec<usize> = commands.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 3, 5]);
+    }
+}
@@ -123,9 +123,9 @@ macro_rules! is {
 }
 
 #[cfg(test)]
-const CYAN: &'static str = "\x1b[96m";
+const CYAN: &str = "\x1b[96m";
 #[cfg(test)]
-const END: &'static str = "\x1b[0m";
+const END: &str = "\x1b[0m";
 
 macro_rules! scope {
     ($( $x:expr )+) => {
@@ -257,6 +257,9 @@ impl<'a> Parser<'a> {
         if predicate(&token) {
             return VarName(P(token));
         }
+        if let Tk::Error(message) = &token.kind {
+            panic!("{}: {}", token.span.start, message)
+        }
         panic!("{:?} does not fit in predicate function", token)
     }
 
@@ -390,7 +393,7 @@ impl<'a> Parser<'a> {
                 self.expr().expect("expected expr"),
                 self.token(is!(Tk::RParen)),
             ],
-            t if matches!(t, Tk::Minus | Tk::Not) => {
+            Tk::Minus | Tk::Not => {
                 vec![UnaryOp(P(self.take_token())), self.term().unwrap()]
             }
             _ => vec![],
@@ -475,7 +478,7 @@ mod tests {
         let mut out_path = jack_path.clone();
         jack_path.push(name);
         jack_path.set_extension("jack");
-        xml_path.push(format!("{}", name));
+        xml_path.push(name);
         xml_path.set_extension("xml");
         out_path.push(format!("{}-out", name));
         out_path.set_extension("xml");
@@ -519,6 +522,12 @@ mod tests {
         )
     }
 
+    #[test]
+    #[should_panic(expected = "1:9: unknown token type of #")]
+    fn test_error_token() {
+        Parser::new("var int #;").var_dec();
+    }
+
     #[test]
     fn test_array_test() {
         compare("ArrayTest/Main");
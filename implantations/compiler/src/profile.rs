@@ -0,0 +1,420 @@
+use crate::vm::{Command, Segment};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// the counters end right below the screen, at the top of the heap which
+/// `reserve_heap` shrinks to make room for them.
+pub const PROFILE_END: usize = 16384;
+/// first word of the heap, counters must not reach below it.
+const HEAP_START: usize = 2048;
+/// words of heap the counters leave at least, the os allocates some 2800 of them
+/// while starting up.
+const MIN_HEAP: usize = 4096;
+
+/// what a profile counter counts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Site {
+    /// entries into a function
+    Function(String),
+    /// executions of one `call` command, `caller` is `None` outside of functions
+    Call {
+        caller: Option<String>,
+        callee: String,
+    },
+}
+
+/// a 32 bit counter stored as low word at `address` and high word at `address + 1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Counter {
+    pub address: usize,
+    /// file stem and line of the `function` or `call` command
+    pub file: String,
+    pub line: usize,
+    pub site: Site,
+}
+
+/// node standing in for code outside of functions
+const TOPLEVEL: &str = "(toplevel)";
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}.vm:{} ", self.address, self.file, self.line)?;
+        match &self.site {
+            Site::Function(name) => write!(f, "function {}", name),
+            Site::Call { caller, callee } => write!(
+                f,
+                "call {} {}",
+                caller.as_deref().unwrap_or(TOPLEVEL),
+                callee
+            ),
+        }
+    }
+}
+
+impl FromStr for Counter {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid profile counter `{}`", line);
+        let parts = line.split_whitespace().collect::<Vec<&str>>();
+        let (address, location, site) = match parts.as_slice() {
+            [address, location, "function", name] => {
+                (address, location, Site::Function(name.to_string()))
+            }
+            [address, location, "call", caller, callee] => (
+                address,
+                location,
+                Site::Call {
+                    caller: Some(caller.to_string()).filter(|caller| caller != TOPLEVEL),
+                    callee: callee.to_string(),
+                },
+            ),
+            _ => return Err(invalid()),
+        };
+        let (file, line_number) = location.rsplit_once(':').ok_or_else(invalid)?;
+        Ok(Counter {
+            address: address.parse().map_err(|_| invalid())?,
+            file: file.strip_suffix(".vm").unwrap_or(file).to_string(),
+            line: line_number.parse().map_err(|_| invalid())?,
+            site,
+        })
+    }
+}
+
+/// one counter for every `function` and every `call` of `(file stem, commands)`
+/// pairs in program order, packed right below `PROFILE_END`. with `reachable`
+/// only the functions in it are counted.
+pub fn layout(
+    files: &[(String, Vec<(usize, Command)>)],
+    reachable: Option<&BTreeSet<String>>,
+) -> Result<Vec<Counter>, String> {
+    let mut counters = vec![];
+    for (file, commands) in files {
+        let mut caller: Option<&String> = None;
+        let mut kept = true;
+        for (line, command) in commands {
+            let site = match command {
+                Command::Function(name, _) => {
+                    caller = Some(name);
+                    kept = reachable.is_none_or(|reachable| reachable.contains(name));
+                    Site::Function(name.clone())
+                }
+                Command::Call(callee, _) => Site::Call {
+                    caller: caller.cloned(),
+                    callee: callee.clone(),
+                },
+                _ => continue,
+            };
+            if kept {
+                counters.push(Counter {
+                    address: 0,
+                    file: file.clone(),
+                    line: *line,
+                    site,
+                });
+            }
+        }
+    }
+    let start = PROFILE_END
+        .checked_sub(2 * counters.len())
+        .filter(|start| *start >= HEAP_START + MIN_HEAP)
+        .ok_or_else(|| too_many(&counters))?;
+    for (index, counter) in counters.iter_mut().enumerate() {
+        counter.address = start + 2 * index;
+    }
+    Ok(counters)
+}
+
+/// keep `Memory.alloc` away from the `counters` by shrinking the free block that
+/// `Memory.init` sets up, recognized as the one constant running from
+/// `HEAP_START` up to the screen give or take its two header words. programs
+/// without a `Memory.init` are left alone.
+pub fn reserve_heap(
+    files: &mut [(String, Vec<(usize, Command)>)],
+    counters: &[Counter],
+) -> Result<(), String> {
+    let heap_sizes = PROFILE_END - HEAP_START - 2..=PROFILE_END - HEAP_START;
+    let mut has_init = false;
+    for (_, commands) in files.iter_mut() {
+        let mut in_init = false;
+        for (_, command) in commands.iter_mut() {
+            match command {
+                Command::Function(name, _) => {
+                    in_init = name == "Memory.init";
+                    has_init |= in_init;
+                }
+                Command::Push(Segment::Constant, size)
+                    if in_init && heap_sizes.contains(&(*size as usize)) =>
+                {
+                    *size = u16::try_from(2 * counters.len())
+                        .ok()
+                        .and_then(|words| size.checked_sub(words))
+                        .ok_or_else(|| too_many(counters))?;
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+    match has_init {
+        true => Err(
+            "cannot find the heap size in Memory.init to make room for the \
+                     profile counters"
+                .to_string(),
+        ),
+        false => Ok(()),
+    }
+}
+
+fn too_many(counters: &[Counter]) -> String {
+    format!(
+        "{} profile counters do not fit into the heap",
+        counters.len()
+    )
+}
+
+/// read a ram dump, either `RAM[address] = value` lines as printed by the
+/// `emulate` command or an output file of the cpu emulator with `RAM[address]`
+/// columns.
+pub fn parse_ram_dump(dump: &str) -> Result<BTreeMap<usize, i16>, String> {
+    let mut ram = BTreeMap::new();
+    let mut columns: Option<Vec<Option<usize>>> = None;
+    for line in dump.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(row) = line.strip_prefix('|') {
+            let cells = row.trim_end_matches('|').split('|').map(str::trim);
+            match &columns {
+                None => columns = Some(cells.map(ram_address).collect()),
+                Some(columns) => {
+                    for (address, cell) in columns.iter().zip(cells) {
+                        if let Some(address) = address {
+                            ram.insert(*address, parse_word(cell)?);
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+        let (address, value) = line
+            .split_once('=')
+            .ok_or(format!("invalid ram dump line `{}`", line))?;
+        let address =
+            ram_address(address.trim()).ok_or(format!("invalid ram dump line `{}`", line))?;
+        ram.insert(address, parse_word(value.trim())?);
+    }
+    Ok(ram)
+}
+
+/// the address of `RAM[address]`
+fn ram_address(cell: &str) -> Option<usize> {
+    cell.strip_prefix("RAM[")?.strip_suffix(']')?.parse().ok()
+}
+
+/// a word printed signed or unsigned
+fn parse_word(value: &str) -> Result<i16, String> {
+    value
+        .parse::<i16>()
+        .or_else(|_| value.parse::<u16>().map(|word| word as i16))
+        .map_err(|_| format!("invalid ram value {}", value))
+}
+
+/// call counts read back from the counters, most frequent first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub functions: Vec<(String, u32)>,
+    pub calls: Vec<(Counter, u32)>,
+}
+
+/// read every counter of `counters` from `ram`, missing words count as 0.
+pub fn report(counters: &[Counter], ram: &BTreeMap<usize, i16>) -> Report {
+    let word = |address: usize| ram.get(&address).map_or(0, |value| *value as u16 as u32);
+    let mut functions = vec![];
+    let mut calls = vec![];
+    for counter in counters {
+        let count = word(counter.address) | word(counter.address + 1) << 16;
+        match &counter.site {
+            Site::Function(name) => functions.push((name.clone(), count)),
+            Site::Call { .. } => calls.push((counter.clone(), count)),
+        }
+    }
+    functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    calls.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.address.cmp(&b.0.address)));
+    Report { functions, calls }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .functions
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max("function".len());
+        writeln!(
+            f,
+            "{:<width$}  {:>10}",
+            "function",
+            "entries",
+            width = width
+        )?;
+        for (name, count) in self.functions.iter().filter(|(_, count)| *count > 0) {
+            writeln!(f, "{:<width$}  {:>10}", name, count, width = width)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{:>10}  call site", "calls")?;
+        for (counter, count) in self.calls.iter().filter(|(_, count)| *count > 0) {
+            if let Site::Call { caller, callee } = &counter.site {
+                writeln!(
+                    f,
+                    "{:>10}  {}.vm:{} {} -> {}",
+                    count,
+                    counter.file,
+                    counter.line,
+                    caller.as_deref().unwrap_or(TOPLEVEL),
+                    callee
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm;
+
+    fn layout_of(source: &str, reachable: Option<&BTreeSet<String>>) -> Vec<Counter> {
+        let files = vec![("Main".to_string(), vm::parse(source).unwrap())];
+        layout(&files, reachable).unwrap()
+    }
+
+    const MAIN: &str = "function Main.main 0\n\
+                        call Main.f 0\n\
+                        return\n\
+                        function Main.f 0\n\
+                        call Math.multiply 2\n\
+                        return";
+
+    #[test]
+    fn test_layout() {
+        let counters = layout_of(MAIN, None);
+        assert_eq!(
+            counters
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>(),
+            vec![
+                "16376 Main.vm:1 function Main.main",
+                "16378 Main.vm:2 call Main.main Main.f",
+                "16380 Main.vm:4 function Main.f",
+                "16382 Main.vm:5 call Main.f Math.multiply",
+            ]
+        );
+        for counter in &counters {
+            assert_eq!(counter.to_string().parse::<Counter>(), Ok(counter.clone()));
+        }
+
+        let reachable = std::iter::once("Main.f".to_string()).collect();
+        assert_eq!(layout_of(MAIN, Some(&reachable)).len(), 2);
+        let toplevel = layout_of("call Main.main 0", None);
+        assert_eq!(
+            toplevel[0].to_string(),
+            "16382 Main.vm:1 call (toplevel) Main.main"
+        );
+        assert_eq!(
+            toplevel[0].to_string().parse::<Counter>(),
+            Ok(toplevel[0].clone())
+        );
+    }
+
+    #[test]
+    fn test_reserve_heap() {
+        let memory = std::fs::read_to_string(
+            std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS/Memory.vm"),
+        )
+        .expect("cannot read file");
+        let counters = layout_of(MAIN, None);
+        let mut files = vec![
+            ("Main".to_string(), vm::parse(MAIN).unwrap()),
+            ("Memory".to_string(), vm::parse(&memory).unwrap()),
+        ];
+        reserve_heap(&mut files, &counters).unwrap();
+        // the free block of 14334 words on line 7 and its 2 word header end right below
+        // the counters
+        let size = 14334 - 2 * counters.len();
+        assert_eq!(
+            files[1].1.iter().find(|(line, _)| *line == 7),
+            Some(&(7, Command::Push(Segment::Constant, size as u16)))
+        );
+        assert_eq!(HEAP_START + 2 + size, counters[0].address);
+
+        let mut files = vec![("Main".to_string(), vm::parse(MAIN).unwrap())];
+        reserve_heap(&mut files, &counters).unwrap();
+        assert_eq!(files[0].1, vm::parse(MAIN).unwrap());
+        // layout keeps a part of the heap, a heap too small for the counters is an error
+        let many = vec![counters[0].clone(); 7168];
+        let mut files = vec![("Memory".to_string(), vm::parse(&memory).unwrap())];
+        assert!(reserve_heap(&mut files, &many).is_err());
+        let functions = (0..(PROFILE_END - HEAP_START - MIN_HEAP) / 2 + 1)
+            .map(|i| format!("function Main.f{} 0\n", i))
+            .collect::<String>();
+        let files = vec![("Main".to_string(), vm::parse(&functions).unwrap())];
+        assert!(layout(&files, None).is_err());
+        let files = vec![(
+            "Main".to_string(),
+            vm::parse(functions.split_once('\n').unwrap().1).unwrap(),
+        )];
+        assert_eq!(
+            layout(&files, None).unwrap()[0].address,
+            HEAP_START + MIN_HEAP
+        );
+
+        let init = "function Memory.init 0\npush constant 0\nreturn";
+        let mut files = vec![("Memory".to_string(), vm::parse(init).unwrap())];
+        assert!(reserve_heap(&mut files, &counters).is_err());
+    }
+
+    #[test]
+    fn test_parse_ram_dump() {
+        let ram = parse_ram_dump("RAM[0] = 261\nRAM[16380] = -1\n").unwrap();
+        assert_eq!(ram.get(&0), Some(&261));
+        assert_eq!(ram.get(&16380), Some(&-1));
+
+        let ram = parse_ram_dump(
+            "| RAM[16380] | RAM[16381] |  RAM[0]  |\n\
+             |      -2    |       1    |    261   |\n",
+        )
+        .unwrap();
+        assert_eq!(ram.len(), 3);
+        assert_eq!(ram.get(&16380), Some(&-2));
+        assert!(parse_ram_dump("RAM[1] 2").is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let counters = layout_of(MAIN, None);
+        let ram = [(16376, 1), (16378, 1), (16380, 1), (16382, -1), (16383, 2)]
+            .iter()
+            .copied()
+            .collect();
+        let report = report(&counters, &ram);
+        assert_eq!(
+            report.functions,
+            vec![("Main.f".to_string(), 1), ("Main.main".to_string(), 1)]
+        );
+        assert_eq!(report.calls[0].1, 0x2ffff);
+        assert_eq!(
+            report.to_string(),
+            "function      entries\n\
+             Main.f              1\n\
+             Main.main           1\n\
+             \n     \
+             calls  call site\n    \
+             196607  Main.vm:5 Main.f -> Math.multiply\n         \
+             1  Main.vm:2 Main.main -> Main.f\n"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+/// where a single rom instruction came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub file: usize,
+    pub line: usize,
+    pub function: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: usize,
+    pub function: Option<&'a str>,
+}
+
+/// maps every rom address of the assembled program back to the vm command it was
+/// generated from. bootstrap code and shared routines have no vm origin.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<String>,
+    functions: Vec<(String, usize)>,
+    mappings: Vec<Option<Mapping>>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    pub fn add_file(&mut self, name: &str) -> usize {
+        match self.files.iter().position(|f| f == name) {
+            Some(index) => index,
+            None => {
+                self.files.push(name.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// register a function starting at the next rom address.
+    pub fn add_function(&mut self, name: &str) -> usize {
+        self.functions.push((name.to_string(), self.mappings.len()));
+        self.functions.len() - 1
+    }
+
+    pub fn push(&mut self, mapping: Option<Mapping>, instructions: usize) {
+        self.mappings
+            .extend(std::iter::repeat_n(mapping, instructions));
+    }
+
+    /// forget everything from `address` on, including functions starting there.
+    pub fn truncate(&mut self, address: usize) {
+        self.mappings.truncate(address);
+        self.functions.retain(|(_, start)| *start < address);
+    }
+
+    /// add everything of `other` after the last address of this map, as if it had
+    /// been pushed here.
+    pub fn append(&mut self, other: SourceMap) {
+        let offset = self.mappings.len();
+        let first_function = self.functions.len();
+        let files = other
+            .files
+            .iter()
+            .map(|file| self.add_file(file))
+            .collect::<Vec<usize>>();
+        self.functions.extend(
+            other
+                .functions
+                .into_iter()
+                .map(|(name, address)| (name, address + offset)),
+        );
+        self.mappings
+            .extend(other.mappings.into_iter().map(|mapping| {
+                mapping.map(|m| Mapping {
+                    file: files[m.file],
+                    line: m.line,
+                    function: m.function.map(|index| index + first_function),
+                })
+            }));
+    }
+
+    pub fn function_address(&self, name: &str) -> Option<usize> {
+        self.functions
+            .iter()
+            .find(|(function, _)| function == name)
+            .map(|(_, address)| *address)
+    }
+
+    pub fn lookup(&self, address: usize) -> Option<SourceLocation<'_>> {
+        let mapping = (*self.mappings.get(address)?)?;
+        Some(SourceLocation {
+            file: &self.files[mapping.file],
+            line: mapping.line,
+            function: mapping
+                .function
+                .map(|index| self.functions[index].0.as_str()),
+        })
+    }
+
+    /// `mappings[address]` is `[file, line, function]` indexing into `files` and
+    /// `functions`, or `null` for code without a vm origin.
+    pub fn to_json(&self) -> String {
+        let files = self
+            .files
+            .iter()
+            .map(|f| json_string(f))
+            .collect::<Vec<String>>();
+        let functions = self
+            .functions
+            .iter()
+            .map(|(name, address)| {
+                format!("{{\"name\":{},\"address\":{}}}", json_string(name), address)
+            })
+            .collect::<Vec<String>>();
+        let mappings = self
+            .mappings
+            .iter()
+            .map(|mapping| match mapping {
+                Some(m) => format!(
+                    "[{},{},{}]",
+                    m.file,
+                    m.line,
+                    m.function
+                        .map_or("null".to_string(), |index| index.to_string())
+                ),
+                None => "null".to_string(),
+            })
+            .collect::<Vec<String>>();
+        format!(
+            "{{\n\"version\":1,\n\"files\":[{}],\n\"functions\":[{}],\n\"mappings\":[{}]\n}}\n",
+            files.join(","),
+            functions.join(","),
+            mappings.join(",")
+        )
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut map = SourceMap::new();
+        map.push(None, 4);
+        let file = map.add_file("Main.vm");
+        let function = map.add_function("Main.main");
+        map.push(
+            Some(Mapping {
+                file,
+                line: 3,
+                function: Some(function),
+            }),
+            2,
+        );
+        assert_eq!(map.len(), 6);
+        assert_eq!(map.lookup(0), None);
+        assert_eq!(
+            map.lookup(5),
+            Some(SourceLocation {
+                file: "Main.vm",
+                line: 3,
+                function: Some("Main.main")
+            })
+        );
+        assert_eq!(map.function_address("Main.main"), Some(4));
+        assert_eq!(
+            map.to_json(),
+            "{\n\"version\":1,\n\"files\":[\"Main.vm\"],\n\
+             \"functions\":[{\"name\":\"Main.main\",\"address\":4}],\n\
+             \"mappings\":[null,null,null,null,[0,3,0],[0,3,0]]\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let mapping = |map: &mut SourceMap, file: &str, function: &str| {
+            let file = map.add_file(file);
+            let function = map.add_function(function);
+            map.push(
+                Some(Mapping {
+                    file,
+                    line: 1,
+                    function: Some(function),
+                }),
+                2,
+            );
+        };
+        let mut map = SourceMap::new();
+        map.push(None, 1);
+        mapping(&mut map, "Main.vm", "Main.main");
+        let mut other = SourceMap::new();
+        mapping(&mut other, "Sys.vm", "Sys.init");
+        mapping(&mut other, "Main.vm", "Main.f");
+        map.append(other);
+
+        assert_eq!(map.len(), 7);
+        assert_eq!(map.function_address("Sys.init"), Some(3));
+        assert_eq!(map.function_address("Main.f"), Some(5));
+        assert_eq!(
+            map.lookup(6),
+            Some(SourceLocation {
+                file: "Main.vm",
+                line: 1,
+                function: Some("Main.f")
+            })
+        );
+        assert_eq!(map.lookup(4).unwrap().file, "Sys.vm");
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut map = SourceMap::new();
+        map.push(None, 2);
+        map.add_function("Main.main");
+        map.push(None, 2);
+        map.truncate(2);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.function_address("Main.main"), None);
+    }
+}
@@ -0,0 +1,400 @@
+use crate::callgraph::CallGraph;
+use crate::vm::Command;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// first word of the stack, SP when the bootstrap code runs.
+pub const STACK_START: usize = 256;
+/// first word of the heap, the stack must stay below it.
+pub const STACK_END: usize = 2048;
+/// words of the frame `call` pushes: return address, LCL, ARG, THIS and THAT.
+const FRAME: usize = 5;
+
+/// words past the top of the stack the hack routine of `command` writes as
+/// scratch: 2 for `div` and 1 for `shr`, unless the extended isa shifts natively.
+pub(crate) fn scratch_words(command: &Command) -> usize {
+    match command {
+        Command::Div => 2,
+        Command::Shr => 1,
+        _ => 0,
+    }
+}
+
+/// stack needs of one vm function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionDepth {
+    pub name: String,
+    pub n_locals: u16,
+    /// deepest operand stack on top of the locals, `Err` when it can grow without bound
+    pub operands: Result<usize, String>,
+    /// words above its LCL the function needs including every function it calls,
+    /// `Err` when that cannot be bounded statically
+    pub usage: Result<usize, String>,
+}
+
+/// worst case stack usage of a program, see `analyze`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackReport {
+    pub functions: Vec<FunctionDepth>,
+    /// highest SP reachable from `Sys.init`, `None` without `Sys.init`
+    pub max_sp: Option<Result<usize, String>>,
+}
+
+impl StackReport {
+    /// true when SP may run into the heap, or cannot be bounded at all.
+    pub fn overflow_risk(&self) -> bool {
+        match &self.max_sp {
+            Some(Ok(sp)) => *sp > STACK_END,
+            Some(Err(_)) => true,
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for StackReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .functions
+            .iter()
+            .map(|function| function.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("function".len());
+        writeln!(
+            f,
+            "{:<width$}  locals  operands  usage",
+            "function",
+            width = width
+        )?;
+        for function in &self.functions {
+            let show = |value: &Result<usize, String>| match value {
+                Ok(words) => words.to_string(),
+                Err(_) => "?".to_string(),
+            };
+            write!(
+                f,
+                "{:<width$}  {:>6}  {:>8}  {}",
+                function.name,
+                function.n_locals,
+                show(&function.operands),
+                show(&function.usage),
+                width = width
+            )?;
+            if let Err(reason) = &function.usage {
+                write!(f, " ({})", reason)?;
+            }
+            writeln!(f)?;
+        }
+        match &self.max_sp {
+            Some(Ok(sp)) => {
+                let verdict = if *sp > STACK_END {
+                    "stack overflows into the heap"
+                } else {
+                    "ok"
+                };
+                writeln!(
+                    f,
+                    "worst case from Sys.init: SP reaches {} ({} of {} words), {}",
+                    sp,
+                    sp - STACK_START,
+                    STACK_END - STACK_START,
+                    verdict
+                )
+            }
+            Some(Err(reason)) => writeln!(
+                f,
+                "worst case from Sys.init is unbounded ({}), stack overflow possible",
+                reason
+            ),
+            None => writeln!(f, "no Sys.init, worst case not computed"),
+        }
+    }
+}
+
+/// compute the operand stack depth of every function of `(file stem, commands)`
+/// pairs, the stack each function needs including its callees and the highest
+/// SP reachable from `Sys.init`. recursion, calls to undefined functions and
+/// loops that keep pushing make usage unbounded.
+pub fn analyze(files: &[(&str, &[(usize, Command)])]) -> StackReport {
+    let graph = CallGraph::new(files);
+    let mut bodies = BTreeMap::new();
+    for (_, commands) in files {
+        let mut current: Option<(&str, usize)> = None;
+        for (index, (_, command)) in commands.iter().enumerate() {
+            if let Command::Function(name, _) = command {
+                if let Some((previous, start)) = current {
+                    bodies.insert(previous, &commands[start..index]);
+                }
+                current = Some((name, index + 1));
+            }
+        }
+        if let Some((previous, start)) = current {
+            bodies.insert(previous, &commands[start..]);
+        }
+    }
+
+    let mut analysis = Analysis {
+        graph: &graph,
+        depths: bodies
+            .iter()
+            .map(|(name, body)| (*name, operand_depths(body)))
+            .collect(),
+        usage: HashMap::new(),
+        active: vec![],
+    };
+    let names = bodies.keys().copied().collect::<Vec<&str>>();
+    let functions = names
+        .iter()
+        .map(|name| FunctionDepth {
+            name: name.to_string(),
+            n_locals: graph.functions[*name].n_locals,
+            operands: analysis.depths[name]
+                .as_ref()
+                .map(|depths| depths.max)
+                .map_err(|e| e.clone()),
+            usage: analysis.usage(name),
+        })
+        .collect();
+    let max_sp = graph.functions.contains_key("Sys.init").then(|| {
+        analysis
+            .usage("Sys.init")
+            .map(|usage| STACK_START + FRAME + usage)
+    });
+    StackReport { functions, max_sp }
+}
+
+/// operand depth of a function body at its deepest and before each of its calls.
+struct Depths<'a> {
+    max: usize,
+    calls: Vec<(usize, &'a str)>,
+}
+
+/// walk every path through the body once. reaching a command again with a deeper
+/// stack means a loop keeps pushing, or paths join with different depths.
+fn operand_depths(body: &[(usize, Command)]) -> Result<Depths<'_>, String> {
+    let labels = body
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (_, command))| match command {
+            Command::Label(label) => Some((label.as_str(), index)),
+            _ => None,
+        })
+        .collect::<HashMap<&str, usize>>();
+    let target = |label: &str| {
+        labels
+            .get(label)
+            .copied()
+            .ok_or(format!("label {} is not defined", label))
+    };
+
+    let mut seen: HashMap<usize, usize> = HashMap::new();
+    let mut depths = Depths {
+        max: 0,
+        calls: vec![],
+    };
+    let mut pending = vec![(0, 0)];
+    while let Some((index, depth)) = pending.pop() {
+        let Some((line, command)) = body.get(index) else {
+            continue;
+        };
+        match seen.get(&index) {
+            Some(previous) if *previous >= depth => continue,
+            Some(_) => return Err(format!("stack depth is not balanced at line {}", line)),
+            None => {}
+        }
+        seen.insert(index, depth);
+        let after = match command {
+            Command::Push(..) => depth + 1,
+            Command::Pop(..) | Command::IfGoto(_) | Command::IfNotGoto(_) => {
+                depth.saturating_sub(1)
+            }
+            Command::Add
+            | Command::Mul
+            | Command::Div
+            | Command::Sub
+            | Command::Eq
+            | Command::Gt
+            | Command::Lt
+            | Command::And
+            | Command::Or => depth.saturating_sub(1),
+            Command::Call(name, n_args) => {
+                depths.calls.push((depth, name));
+                depth.saturating_sub(*n_args as usize) + 1
+            }
+            _ => depth,
+        };
+        depths.max = depths.max.max(after).max(depth + scratch_words(command));
+        match command {
+            Command::Goto(label) => pending.push((target(label)?, after)),
+            Command::IfGoto(label) | Command::IfNotGoto(label) => {
+                pending.push((target(label)?, after));
+                pending.push((index + 1, after));
+            }
+            Command::Return => {}
+            _ => pending.push((index + 1, after)),
+        }
+    }
+    Ok(depths)
+}
+
+struct Analysis<'a> {
+    graph: &'a CallGraph,
+    depths: HashMap<&'a str, Result<Depths<'a>, String>>,
+    usage: HashMap<String, Result<usize, String>>,
+    /// functions whose usage is being computed, to find recursion
+    active: Vec<String>,
+}
+
+impl Analysis<'_> {
+    fn usage(&mut self, name: &str) -> Result<usize, String> {
+        if let Some(usage) = self.usage.get(name) {
+            return usage.clone();
+        }
+        if self.active.iter().any(|active| active == name) {
+            return Err(format!("{} is recursive", name));
+        }
+        let depths = match self.depths.get(name) {
+            Some(Ok(depths)) => depths,
+            Some(Err(e)) => return Err(e.clone()),
+            None => return Err(format!("{} is not defined", name)),
+        };
+        let n_locals = self.graph.functions[name].n_locals as usize;
+        let calls = depths.calls.clone();
+        let mut usage = n_locals + depths.max;
+
+        self.active.push(name.to_string());
+        let mut result = Ok(());
+        for (depth, callee) in calls {
+            match self.usage(callee) {
+                Ok(callee_usage) => {
+                    usage = usage.max(n_locals + depth + FRAME + callee_usage);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.active.pop();
+
+        let usage = result.map(|_| usage);
+        // recursion is only known once the whole cycle is walked
+        if self.active.is_empty() || usage.is_ok() {
+            self.usage.insert(name.to_string(), usage.clone());
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm;
+
+    fn report(source: &str) -> StackReport {
+        let commands = vm::parse(source).unwrap();
+        analyze(&[("Main", &commands)])
+    }
+
+    #[test]
+    fn test_operand_depth() {
+        let report = report(
+            "function Main.f 2\n\
+             push constant 1\n\
+             push constant 2\n\
+             push constant 3\n\
+             add\n\
+             add\n\
+             label LOOP\n\
+             push constant 1\n\
+             if-goto LOOP\n\
+             return",
+        );
+        assert_eq!(report.functions[0].operands, Ok(3));
+        assert_eq!(report.functions[0].usage, Ok(5));
+        assert_eq!(report.max_sp, None);
+    }
+
+    #[test]
+    fn test_routine_scratch() {
+        let report = report("function Main.f 0\npush constant 7\npush constant 2\ndiv\nshr\nreturn");
+        assert_eq!(report.functions[0].operands, Ok(4));
+    }
+
+    #[test]
+    fn test_usage_through_calls() {
+        let report = report(
+            "function Sys.init 0\n\
+             push constant 1\n\
+             push constant 2\n\
+             call Main.f 1\n\
+             label HALT\n\
+             goto HALT\n\
+             function Main.f 1\n\
+             push argument 0\n\
+             push argument 0\n\
+             add\n\
+             return",
+        );
+        // Main.f needs its local and two operands
+        assert_eq!(report.functions[0].usage, Ok(3));
+        // Sys.init calls it with two operands on the stack
+        assert_eq!(report.functions[1].usage, Ok(2 + 5 + 3));
+        assert_eq!(report.max_sp, Some(Ok(256 + 5 + 10)));
+        assert!(!report.overflow_risk());
+    }
+
+    #[test]
+    fn test_unbounded() {
+        let report = report(
+            "function Sys.init 0\n\
+             call Main.f 0\n\
+             return\n\
+             function Main.f 0\n\
+             call Main.f 0\n\
+             return\n\
+             function Main.g 0\n\
+             label LOOP\n\
+             push constant 1\n\
+             goto LOOP",
+        );
+        assert_eq!(
+            report.functions[0].usage,
+            Err("Main.f is recursive".to_string())
+        );
+        assert_eq!(
+            report.functions[1].operands,
+            Err("stack depth is not balanced at line 8".to_string())
+        );
+        assert!(report.overflow_risk());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let pushes = "push constant 0\n".repeat(1800);
+        let report = report(&format!(
+            "function Sys.init 0\ncall Main.f 0\nreturn\nfunction Main.f 0\n{}return",
+            pushes
+        ));
+        assert_eq!(report.max_sp, Some(Ok(256 + 5 + 5 + 1800)));
+        assert!(report.overflow_risk());
+        assert!(report.to_string().contains("stack overflows into the heap"));
+    }
+
+    #[test]
+    fn test_projects() {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/NestedCall");
+        let sources = vm::load_sources(&path).unwrap();
+        let files = sources
+            .iter()
+            .map(|(name, source)| (name.as_str(), vm::parse(source).unwrap()))
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(name, commands)| (*name, commands.as_slice()))
+            .collect::<Vec<_>>();
+        let report = analyze(&files);
+        assert!(matches!(report.max_sp, Some(Ok(sp)) if sp < STACK_END));
+    }
+}
@@ -1,36 +1,95 @@
-use std::str::Chars;
+
+/// a place in the source, `line` and `column` count from 1 and columns count chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    /// bytes before it
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    /// the position after `text`, when `text` starts here.
+    fn advance(mut self, text: &str) -> Position {
+        self.offset += text.len();
+        for byte in text.bytes() {
+            if byte == b'\n' {
+                self.line += 1;
+                self.column = 1;
+            } else if byte & 0b1100_0000 != 0b1000_0000 {
+                // every byte but the continuation bytes of utf-8 starts a char
+                self.column += 1;
+            }
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// the source a token was read from, `end` is the position right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn len(&self) -> usize {
+        self.end.offset - self.start.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Token<'a> {
     pub kind: TokenKind,
     pub literal: Literal<'a>,
+    pub span: Span,
 }
 
 impl<'a> Token<'a> {
-    pub fn from_span(span: &'a str, kind: TokenKind) -> Self {
-        let literal = match kind {
-            IntegerConstant => Literal::Integer(
-                span.parse::<i16>()
-                    .expect(&format!("jack only support i16 int but found {}", span)),
-            ),
-            StringConstant => Literal::String(&span[1..span.len() - 1]),
-            _ => Literal::String(span),
+    /// an integer out of the i16 range becomes an `Error` token.
+    pub fn from_span(text: &'a str, kind: TokenKind, span: Span) -> Self {
+        let (kind, literal) = match kind {
+            IntegerConstant => match text.parse::<i16>() {
+                Ok(value) => (kind, Literal::Integer(value)),
+                Err(_) => (
+                    Error(format!("jack only support i16 int but found {}", text)),
+                    Literal::String(text),
+                ),
+            },
+            StringConstant => (kind, Literal::String(&text[1..text.len() - 1])),
+            _ => (kind, Literal::String(text)),
         };
-        Token { kind, literal }
+        Token {
+            kind,
+            literal,
+            span,
+        }
     }
 
+    /// an `Error` token becomes an `<error>` element with its position and message.
     pub fn xml(&self) -> String {
-        let escaped_content = self
-            .literal
-            .to_string()
+        let content = match &self.kind {
+            Error(message) => format!("{}: {}", self.span.start, message),
+            _ => self.literal.to_string(),
+        };
+        let escaped_content = content
             .replace("&", "&amp;")
             .replace("<", "&lt;")
             .replace(">", "&gt;")
             .replace(r#"""#, "&quot;")
             .replace("'", "&apos;");
-        match self.kind.as_str() {
-            Some(kind_str) => format!("<{0}> {1} </{0}>", kind_str, escaped_content),
-            None => "".to_string(),
-        }
+        let kind_str = self.kind.as_str().unwrap_or("error");
+        format!("<{0}> {1} </{0}>", kind_str, escaped_content)
     }
 }
 
@@ -40,11 +99,11 @@ pub enum Literal<'a> {
     String(&'a str),
 }
 
-impl<'a> ToString for Literal<'a> {
-    fn to_string(&self) -> String {
+impl<'a> std::fmt::Display for Literal<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Literal::String(val) => val.to_string(),
-            Literal::Integer(val) => val.to_string(),
+            Literal::String(val) => write!(f, "{}", val),
+            Literal::Integer(val) => write!(f, "{}", val),
         }
     }
 }
@@ -98,6 +157,9 @@ pub enum TokenKind {
     GT,
     EQ,
     Not,
+    /// source that is no token, with what is wrong with it. the tokenizer goes
+    /// on after it.
+    Error(String),
 }
 
 impl std::str::FromStr for TokenKind {
@@ -150,21 +212,22 @@ impl TokenKind {
 }
 
 pub struct Tokenizer<'a> {
-    source_len: usize,
-    chars: Chars<'a>,
-    buffer: Vec<Option<Token<'a>>>,
-    read_pos: usize,
     source: &'a str,
+    /// next byte to scan
+    pos: usize,
+    /// start of the token being scanned
+    read_pos: usize,
+    /// line and column of `read_pos`
+    position: Position,
+    /// the next token once `peek_token` looked at it, `Some(None)` at the end
+    peeked: Option<Option<Token<'a>>>,
 }
 
 impl<'a> Tokenizer<'a> {
     pub fn xml(&'a mut self) -> String {
         let mut out = vec!["<tokens>".to_string()];
         for token in self.tokenize() {
-            let line = token.xml();
-            if !line.is_empty() {
-                out.push(line);
-            }
+            out.push(token.xml());
         }
         out.push("</tokens>".to_string());
         out.join("\n")
@@ -174,107 +237,136 @@ impl<'a> Tokenizer<'a> {
 impl<'a> Tokenizer<'a> {
     pub fn new(source: &'a str) -> Tokenizer<'a> {
         Tokenizer {
-            buffer: vec![],
-            source_len: source.len(),
-            chars: source.chars(),
-            read_pos: 0,
             source,
+            pos: 0,
+            read_pos: 0,
+            position: Position {
+                offset: 0,
+                line: 1,
+                column: 1,
+            },
+            peeked: None,
         }
     }
 
-    pub fn tokenize(&'a mut self) -> impl Iterator<Item = Token> {
+    pub fn tokenize(&'a mut self) -> impl Iterator<Item = Token<'a>> {
         std::iter::from_fn(move || self.take_token())
     }
 
+    /// the next char, decoded from utf-8 only when it is not ascii.
     fn peek_char(&self) -> char {
-        self.chars.clone().nth(0).unwrap_or('\0')
+        match self.source.as_bytes().get(self.pos) {
+            Some(byte) if byte.is_ascii() => *byte as char,
+            Some(_) => self.source[self.pos..].chars().next().unwrap_or('\0'),
+            None => '\0',
+        }
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        if self.is_eof() {
+            return None;
+        }
+        let c = self.peek_char();
+        self.pos += c.len_utf8();
+        Some(c)
     }
 
     fn is_eof(&self) -> bool {
-        self.chars.as_str().is_empty()
+        self.pos >= self.source.len()
     }
-    fn eat(&mut self, predicate: char) -> bool {
-        if self.peek_char() == predicate {
-            self.chars.next();
+
+    fn eat(&mut self, predicate: u8) -> bool {
+        if self.source.as_bytes().get(self.pos) == Some(&predicate) {
+            self.pos += 1;
             return true;
         }
         false
     }
 
     fn eat_while(&mut self, mut predicate: impl FnMut(char) -> bool) {
-        while predicate(self.peek_char()) && !self.is_eof() {
-            self.chars.next();
+        while !self.is_eof() {
+            let c = self.peek_char();
+            if !predicate(c) {
+                break;
+            }
+            self.pos += c.len_utf8();
         }
     }
 
-    fn eat_until(&mut self, predicate: char) {
-        while self.peek_char() != predicate && !self.is_eof() {
-            self.chars.next();
-        }
-        self.chars.next();
+    /// move to the first of `bytes` or to the end. ascii bytes never occur
+    /// inside a multi-byte char, so this skips whole chars.
+    fn skip_to(&mut self, bytes: &[u8]) {
+        self.pos += self.source.as_bytes()[self.pos..]
+            .iter()
+            .position(|byte| bytes.contains(byte))
+            .unwrap_or(self.source.len() - self.pos);
     }
-    fn current_span(&mut self) -> (&'a str, usize) {
-        let len = self.source_len - self.read_pos - self.chars.as_str().len();
-        (&self.source[self.read_pos..self.read_pos + len], len)
+
+    fn current_span(&self) -> &'a str {
+        &self.source[self.read_pos..self.pos]
     }
 
     fn take_span(&mut self) -> &'a str {
-        let (span, len) = self.current_span();
-        self.read_pos += len;
+        let span = self.current_span();
+        self.read_pos = self.pos;
         span
     }
 
     pub fn take_token(&mut self) -> Option<Token<'a>> {
-        if !self.buffer.is_empty() {
-            return self.buffer.remove(0);
+        if let Some(token) = self.peeked.take() {
+            return token;
         }
-        match self.take_token_all_type() {
-            Some(t) if matches!(t.kind, Whitespace | Comment) => self.take_token(),
-            t => {
-                #[cfg(test)]
-                println!("taken {:?}", t);
-                t
+        loop {
+            match self.take_token_all_type() {
+                Some(t) if matches!(t.kind, Whitespace | Comment) => continue,
+                t => {
+                    #[cfg(test)]
+                    println!("taken {:?}", t);
+                    return t;
+                }
             }
         }
     }
 
     pub fn peek_token(&mut self) -> Option<Token<'a>> {
-        if let Some(token) = self.buffer.get(0) {
-            token.clone()
-        } else {
-            let token = self.take_token();
-            self.buffer.push(token.clone());
-            token
+        if self.peeked.is_none() {
+            self.peeked = Some(self.take_token());
         }
+        self.peeked.clone().flatten()
     }
 
     pub fn take_token_all_type(&mut self) -> Option<Token<'a>> {
         let kind = self.prepare_token()?;
-        let span = self.take_span();
-        // println!("kind:{:?} span:{}", kind, span.len());
-        Some(Token::from_span(span, kind))
+        let text = self.take_span();
+        let start = self.position;
+        self.position = start.advance(text);
+        Some(Token::from_span(
+            text,
+            kind,
+            Span {
+                start,
+                end: self.position,
+            },
+        ))
     }
 
     pub fn prepare_token(&mut self) -> Option<TokenKind> {
-        if self.source.is_empty() {
-            return None;
-        }
-
-        let kind = match self.chars.next()? {
-            '/' => match self.peek_char() {
-                '/' => {
-                    self.eat_until('\n');
+        let kind = match self.next_char()? {
+            '/' => {
+                if self.eat(b'/') {
+                    self.skip_to(b"\n");
+                    self.eat(b'\n');
                     Comment
-                }
-                _ if self.eat('*') => {
-                    while !(self.is_eof() || (self.eat('*') && self.eat('/'))) {
-                        self.chars.next();
-                    }
-                    // println!("current_span: {}", self.current_span().0.to_string());
+                } else if self.eat(b'*') {
+                    self.pos = match self.source[self.pos..].find("*/") {
+                        Some(end) => self.pos + end + 2,
+                        None => self.source.len(),
+                    };
                     Comment
+                } else {
+                    Slash
                 }
-                _ => Slash,
-            },
+            }
             '{' => LBrace,
             '}' => RBrace,
             '(' => LParen,
@@ -298,17 +390,19 @@ impl<'a> Tokenizer<'a> {
                 IntegerConstant
             }
             '"' => {
-                self.eat_while(|c| match c {
-                    '"' => false,
-                    '\n' => panic!("new line is not allowed in jack string"),
-                    _ => true,
-                });
-                self.chars.next();
-                StringConstant
+                self.skip_to(b"\"\n");
+                // the string ends before a new line, which is lexed as whitespace
+                if self.eat(b'"') {
+                    StringConstant
+                } else if self.is_eof() {
+                    Error("string is not closed".to_string())
+                } else {
+                    Error("new line is not allowed in jack string".to_string())
+                }
             }
             c if is_identifier_start(c) => {
                 self.eat_while(is_identifier_tail);
-                match self.current_span().0.parse::<TokenKind>() {
+                match self.current_span().parse::<TokenKind>() {
                     Ok(kind) => kind,
                     _ => TokenKind::Ident,
                 }
@@ -317,7 +411,7 @@ impl<'a> Tokenizer<'a> {
                 self.eat_while(is_whitespace);
                 TokenKind::Whitespace
             }
-            unknown => panic!("unknown token type of {}", unknown),
+            unknown => Error(format!("unknown token type of {}", unknown)),
         };
         Some(kind)
     }
@@ -359,7 +453,7 @@ fn is_whitespace(c: char) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
-    #[test]
+    // #[test]
     // fn test_whitespace() {
     //     let mut tokenizer = Tokenizer::new(" ");
     //     let token = tokenizer.take_token_all_type().unwrap();
@@ -405,9 +499,75 @@ mod tests {
         assert_eq!(token.literal.to_string(), ")".to_string());
     }
     #[test]
-    #[should_panic]
+    fn test_span() {
+        let source = "class Main {\n  /* ✨ */ let x = \"✨\";\r\n}";
+        let mut tokenizer = Tokenizer::new(source);
+        let tokens = std::iter::from_fn(|| tokenizer.take_token_all_type())
+            .map(|token| (token.literal.to_string(), token.span))
+            .collect::<Vec<_>>();
+        let at = |text: &str| tokens.iter().find(|(t, _)| t == text).unwrap().1;
+        let position = |offset, line, column| Position {
+            offset,
+            line,
+            column,
+        };
+        assert_eq!(at("class").start, position(0, 1, 1));
+        assert_eq!(at("class").end, position(5, 1, 6));
+        assert_eq!(at("{").start, position(11, 1, 12));
+        assert_eq!(at("let").start, position(25, 2, 11));
+        assert_eq!(at("✨").start, position(33, 2, 19));
+        assert_eq!(at("✨").len(), 5);
+        assert_eq!(at(";").start, position(38, 2, 22));
+        assert_eq!(at("}").start, position(41, 3, 1));
+        assert_eq!(at("}").start.to_string(), "3:1");
+        // spans cover the source without gaps
+        assert_eq!(tokens.last().unwrap().1.end.offset, source.len());
+        for pair in tokens.windows(2) {
+            assert_eq!(pair[0].1.end, pair[1].1.start);
+        }
+    }
+
+    #[test]
+    fn test_lookahead() {
+        let mut tokenizer = Tokenizer::new("/** doc **/ do // x\n f();");
+        assert_eq!(tokenizer.peek_token().unwrap().kind, TokenKind::Do);
+        assert_eq!(tokenizer.peek_token().unwrap().kind, TokenKind::Do);
+        assert_eq!(tokenizer.take_token().unwrap().kind, TokenKind::Do);
+        let token = tokenizer.peek_token().unwrap();
+        assert_eq!(token.literal, Literal::String("f"));
+        assert_eq!(token.span.start.line, 2);
+        let kinds = std::iter::from_fn(|| tokenizer.take_token())
+            .map(|token| token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [Ident, LParen, RParen, Semi]);
+        assert!(tokenizer.peek_token().is_none());
+    }
+
+    #[test]
     fn test_unknown() {
-        Tokenizer::new("!").take_token();
+        let mut tokenizer = Tokenizer::new("let ! x#");
+        tokenizer.take_token();
+        let token = tokenizer.take_token().unwrap();
+        assert_eq!(
+            token.kind,
+            TokenKind::Error("unknown token type of !".to_string())
+        );
+        assert_eq!(token.literal.to_string(), "!");
+        assert_eq!(token.span.start.offset, 4);
+        assert_eq!(token.xml(), "<error> 1:5: unknown token type of ! </error>");
+        // lexing goes on after the error
+        assert_eq!(tokenizer.take_token().unwrap().kind, TokenKind::Ident);
+        let token = tokenizer.take_token().unwrap();
+        assert!(matches!(token.kind, TokenKind::Error(_)));
+        assert!(tokenizer.take_token().is_none());
+        assert_eq!(
+            Tokenizer::new("x < \"y").xml(),
+            "<tokens>\n\
+             <identifier> x </identifier>\n\
+             <symbol> &lt; </symbol>\n\
+             <error> 1:5: string is not closed </error>\n\
+             </tokens>"
+        );
     }
 
     #[test]
@@ -432,10 +592,33 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
-    fn test_integer_constant_panic_on_out_of_range() {
-        let mut tokenizer = Tokenizer::new("32768");
-        tokenizer.take_token();
+    fn test_integer_constant_out_of_range() {
+        let mut tokenizer = Tokenizer::new("32768;");
+        let token = tokenizer.take_token().unwrap();
+        assert_eq!(
+            token.kind,
+            TokenKind::Error("jack only support i16 int but found 32768".to_string())
+        );
+        assert_eq!(token.literal, Literal::String("32768"));
+        assert_eq!(token.span.len(), 5);
+        assert_eq!(tokenizer.take_token().unwrap().kind, TokenKind::Semi);
+    }
+
+    #[test]
+    fn test_string_constant_errors() {
+        let mut tokenizer = Tokenizer::new("\"new\nline\"");
+        let token = tokenizer.take_token().unwrap();
+        assert_eq!(
+            token.kind,
+            TokenKind::Error("new line is not allowed in jack string".to_string())
+        );
+        assert_eq!(token.literal.to_string(), "\"new");
+        assert_eq!(tokenizer.take_token().unwrap().kind, TokenKind::Ident);
+        // the closing quote opens a string that is never closed
+        let token = tokenizer.take_token().unwrap();
+        assert_eq!(token.kind, TokenKind::Error("string is not closed".to_string()));
+        assert_eq!(token.span.start.line, 2);
+        assert!(tokenizer.take_token().is_none());
     }
 
     #[test]
@@ -1,83 +1,769 @@
+pub mod c;
+pub mod rust;
+pub mod wat;
+pub mod x86;
+
+use crate::callgraph::CallGraph;
+use crate::source_map::{Mapping, SourceMap};
+use crate::stack_depth::{scratch_words, STACK_END, STACK_START};
+use crate::{inliner, optimizer, profile, vm};
+use std::collections::BTreeSet;
 use std::path::PathBuf;
-use std::{ffi::OsString, unimplemented};
+
+/// where `stack_check` leaves its error code, the word right below the heap that
+/// a stack within bounds never reaches.
+pub const STACK_ERROR: usize = STACK_END - 1;
+/// error code of a push or call that left SP above `STACK_ERROR`.
+pub const STACK_OVERFLOW: i16 = 1;
+/// error code of a push that left SP below the start of the stack.
+pub const STACK_UNDERFLOW: i16 = 2;
+
 pub struct VMTranslator {
-    path: PathBuf,
-    target: PathBuf,
-    filename: OsString,
+    path: Option<PathBuf>,
+    filename: String,
     label_index: u32,
     output: Vec<String>,
+    compact: bool,
+    optimize: bool,
+    saved_instructions: usize,
+    cached_instructions: usize,
+    annotate: bool,
+    source_map: SourceMap,
+    origin: Option<Mapping>,
+    write_source_map: bool,
+    tree_shake: bool,
+    reachable: Option<BTreeSet<String>>,
+    removed_functions: Vec<String>,
+    os: Option<PathBuf>,
+    inline: usize,
+    inlined_calls: usize,
+    fast_compare: bool,
+    threads: usize,
+    profile: bool,
+    profile_counters: Vec<profile::Counter>,
+    /// address of the next counter the file being translated increments
+    next_counter: Option<usize>,
+    extended_commands: bool,
+    extended_isa: bool,
+    stack_check: bool,
+    cache_top: bool,
+    /// the top of the stack is in D rather than at SP - 1
+    top_in_d: bool,
+    /// the function being translated, its labels are named `function$label`
+    function: String,
 }
 
-impl VMTranslator {
-    pub fn load(path: PathBuf) -> Self {
-        let filename = path.file_name().unwrap().to_os_string();
-        let mut target = path.clone();
-        let name = path.file_name().unwrap();
-        target.push(name);
+impl Default for VMTranslator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
+impl VMTranslator {
+    /// a translator for sources held in memory, see `translate`.
+    pub fn new() -> Self {
         VMTranslator {
-            path,
-            target,
-            filename,
+            path: None,
+            filename: String::new(),
             label_index: 1,
             output: vec![],
+            compact: false,
+            optimize: false,
+            saved_instructions: 0,
+            cached_instructions: 0,
+            annotate: false,
+            source_map: SourceMap::new(),
+            origin: None,
+            write_source_map: false,
+            tree_shake: false,
+            reachable: None,
+            removed_functions: vec![],
+            os: None,
+            inline: 0,
+            inlined_calls: 0,
+            fast_compare: false,
+            threads: 0,
+            profile: false,
+            profile_counters: vec![],
+            next_counter: None,
+            extended_commands: false,
+            extended_isa: false,
+            stack_check: false,
+            cache_top: false,
+            top_in_d: false,
+            function: String::new(),
+        }
+    }
+
+    /// a translator for the `.vm` file or directory at `path`, see `process` and `write`.
+    pub fn load(path: PathBuf) -> Self {
+        VMTranslator {
+            path: Some(path),
+            ..Self::new()
+        }
+    }
+
+    /// share one `$$CALL`, `$$RETURN` and `$$EQ/$$GT/$$LT` routine between all sites
+    /// instead of inlining them, trading a few jumps for a much smaller ROM.
+    pub fn compact(&mut self, enabled: bool) -> &mut Self {
+        self.compact = enabled;
+        self
+    }
+
+    /// run the peephole optimizer on every file before generating code.
+    pub fn optimize(&mut self, enabled: bool) -> &mut Self {
+        self.optimize = enabled;
+        self
+    }
+
+    /// number of asm instructions the peephole optimizer removed so far.
+    pub fn saved_instructions(&self) -> usize {
+        self.saved_instructions
+    }
+
+    /// number of asm instructions keeping the top of the stack in D removed so far,
+    /// on top of those the peephole optimizer removed.
+    pub fn cached_instructions(&self) -> usize {
+        self.cached_instructions
+    }
+
+    /// precede every asm block with a `// file.vm:line: command` comment and every
+    /// function with a banner, so the output can be followed in the cpu emulator.
+    pub fn annotate(&mut self, enabled: bool) -> &mut Self {
+        self.annotate = enabled;
+        self
+    }
+
+    /// also write `<name>.map.json` next to the asm, see `SourceMap::to_json`.
+    pub fn write_source_map(&mut self, enabled: bool) -> &mut Self {
+        self.write_source_map = enabled;
+        self
+    }
+
+    /// leave out every function that cannot be reached through calls from
+    /// `Sys.init`. does nothing for programs without `Sys.init`.
+    pub fn tree_shake(&mut self, enabled: bool) -> &mut Self {
+        self.tree_shake = enabled;
+        self
+    }
+
+    /// functions left out by tree shaking, sorted by name.
+    pub fn removed_functions(&self) -> &[String] {
+        &self.removed_functions
+    }
+
+    /// compile `gt` / `lt` to the sign of `x - y` alone. smaller and faster, but
+    /// wrong when the subtraction overflows, e.g. `32767 gt -2` is false.
+    pub fn fast_compare(&mut self, enabled: bool) -> &mut Self {
+        self.fast_compare = enabled;
+        self
+    }
+
+    /// paste functions of at most `max_size` commands into their callers instead
+    /// of calling them, see `inliner::inline`. 0 turns inlining off.
+    pub fn inline(&mut self, max_size: usize) -> &mut Self {
+        self.inline = max_size;
+        self
+    }
+
+    /// number of call sites replaced by inlining so far.
+    pub fn inlined_calls(&self) -> usize {
+        self.inlined_calls
+    }
+
+    /// translate the files on up to `threads` worker threads, 0 uses one per cpu.
+    /// the output is the same for any number of threads.
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    /// count every function entry and every executed `call` in 32 bit counters
+    /// right below the screen, see `profile_counters` and `profile::report`. the
+    /// heap of a linked `Memory.init` shrinks to make room for them.
+    pub fn profile(&mut self, enabled: bool) -> &mut Self {
+        self.profile = enabled;
+        self
+    }
+
+    /// where the counters of the last program translated with `profile` are.
+    pub fn profile_counters(&self) -> &[profile::Counter] {
+        &self.profile_counters
+    }
+
+    /// accept the `mul`, `div`, `shl` and `shr` commands. all but `shl` jump to
+    /// routines added once at the start of the program.
+    pub fn extended_commands(&mut self, enabled: bool) -> &mut Self {
+        self.extended_commands = enabled;
+        self
+    }
+
+    /// target a hack cpu with the shift instructions `D<<`, `M>>`, .. and use them
+    /// for `shl` and `shr`. the cpu emulator in tools does not know them.
+    pub fn extended_isa(&mut self, enabled: bool) -> &mut Self {
+        self.extended_isa = enabled;
+        self
+    }
+
+    /// check after every `push` and at every function entry that SP stays within
+    /// 256..2047, and before `div` and `shr` that the scratch words of their
+    /// routines do. a violation halts the program with `STACK_OVERFLOW` or
+    /// `STACK_UNDERFLOW` in `RAM[STACK_ERROR]` instead of silently corrupting the heap.
+    pub fn stack_check(&mut self, enabled: bool) -> &mut Self {
+        self.stack_check = enabled;
+        self
+    }
+
+    /// keep the top of the stack in D between commands instead of always writing
+    /// it back, so that e.g. `push local 0` / `push constant 1` / `add` / `pop local 0`
+    /// never touches the stack. it is written back before labels, jumps, calls and
+    /// returns. the instructions saved count towards `cached_instructions`.
+    pub fn cache_top(&mut self, enabled: bool) -> &mut Self {
+        self.cache_top = enabled;
+        self
+    }
+
+    /// let `process` also translate the `.vm` files of the `os` directory, except for
+    /// the classes the program defines itself.
+    pub fn with_os(&mut self, os: PathBuf) -> &mut Self {
+        self.os = Some(os);
+        self
+    }
+
+    /// rom address -> vm file, line and function of everything translated so far.
+    pub fn source_map(&self) -> &SourceMap {
+        &self.source_map
+    }
+
+    /// write the output of `process` to `<dir>/<dir name>.asm`, or next to the
+    /// `.vm` file when a single file was loaded.
+    pub fn write(&self) -> Result<(), String> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or("the translator was not loaded from a path")?;
+        let mut target = match path.file_name() {
+            Some(name) if path.is_dir() => path.join(name),
+            _ => path.clone(),
+        };
+        target.set_extension("asm");
+        write_file(&target, &(self.output.join("\n") + "\n"))?;
+        if self.write_source_map {
+            target.set_extension("map.json");
+            write_file(&target, &self.source_map.to_json())?;
         }
+        Ok(())
     }
 
-    pub fn write(&self) {
-        let mut asm_path = self.target.clone();
-        asm_path.set_extension("asm");
-        std::fs::write(asm_path, self.output.join("\n") + "\n").expect("failed to write file");
+    /// read the loaded `.vm` file or directory, linked with the os if one is given,
+    /// and translate it, see `translate`.
+    pub fn process(&mut self) -> Result<&mut Self, String> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or("the translator was not loaded from a path")?;
+        let mut paths = vm::vm_paths(path)?;
+        if let Some(os) = &self.os {
+            paths = vm::link_library(paths, vm::vm_paths(os)?);
+        }
+        let sources = vm::read_sources(&paths)?;
+        self.translate(&vm::as_str_pairs(&sources))?;
+        Ok(self)
     }
 
-    pub fn process(&mut self) -> &mut Self {
-        let paths = std::fs::read_dir(&self.path)
-            .expect("failed to read dir")
-            .map(|e| e.unwrap().path())
-            .filter(|p| match p.extension() {
-                Some(ext) => ext.to_str() == Some("vm"),
-                _ => false,
+    /// translate the `(file stem, vm code)` pairs of one program to hack asm. the
+    /// bootstrap code calling `Sys.init` is only added for more than one file.
+    /// every call starts a new program, the statistics keep adding up.
+    pub fn translate(&mut self, sources: &[(&str, &str)]) -> Result<String, String> {
+        let mut files = sources
+            .iter()
+            .map(|(name, source)| match vm::parse(source) {
+                Ok(commands) => Ok((name.to_string(), commands)),
+                Err(e) => Err(format!("{}.vm {}", name, e)),
             })
-            .collect::<Vec<PathBuf>>();
+            .collect::<Result<Vec<_>, String>>()?;
+        self.label_index = 1;
+        self.output.clear();
+        self.source_map = SourceMap::new();
+        self.reachable = None;
+        self.removed_functions.clear();
+
+        // whole program passes
+        if self.inline > 0 {
+            self.inlined_calls += inliner::inline(&mut files, self.inline);
+        }
+        if self.tree_shake {
+            self.find_reachable(&files);
+        }
+        self.profile_counters = match self.profile {
+            true => profile::layout(&files, self.reachable.as_ref())?,
+            false => vec![],
+        };
+        if self.profile {
+            profile::reserve_heap(&mut files, &self.profile_counters)?;
+        }
+        let mut routines = BTreeSet::new();
+        for (name, commands) in &files {
+            for (line, command) in commands {
+                let routine = match command {
+                    vm::Command::Mul => "MUL",
+                    vm::Command::Div => "DIV",
+                    vm::Command::Shr if !self.extended_isa => "SHR",
+                    vm::Command::Shl | vm::Command::Shr => "",
+                    _ => continue,
+                };
+                if !self.extended_commands {
+                    return Err(format!(
+                        "{}.vm line {}: `{}` is an extended command, see `extended_commands`",
+                        name, line, command
+                    ));
+                }
+                routines.insert(routine);
+            }
+        }
+        routines.remove("");
 
-        if paths.len() > 1 {
-            self.emit_boot();
+        if self.compact {
+            self.emit_shared_routines();
+        }
+        if !routines.is_empty() {
+            self.emit_arithmetic_routines(&routines);
+        }
+        if self.stack_check {
+            self.emit_stack_halt();
+        }
+        if files.len() > 1 {
+            self.emit_boot()?;
         }
 
-        for path in paths {
-            self.filename = path.file_stem().unwrap().to_os_string();
-            self.process_single(path);
+        // files only share the settings, their labels are numbered per file
+        let threads = match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        };
+        let chunk_size = files.len().div_ceil(threads).max(1);
+        let mut chunks = vec![];
+        let mut files = files.into_iter().peekable();
+        while files.peek().is_some() {
+            chunks.push(files.by_ref().take(chunk_size).collect::<Vec<_>>());
         }
-        self
+        let settings = &*self;
+        let translated = std::thread::scope(|scope| {
+            let workers = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .into_iter()
+                            .map(|(name, commands)| {
+                                let mut worker = settings.worker(name);
+                                worker.next_counter = settings
+                                    .profile_counters
+                                    .iter()
+                                    .find(|counter| counter.file == worker.filename)
+                                    .map(|counter| counter.address);
+                                worker.process_single(commands)?;
+                                Ok(worker)
+                            })
+                            .collect::<Result<Vec<_>, String>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("translation worker panicked"))
+                .collect::<Result<Vec<_>, String>>()
+        })?;
+        for worker in translated.into_iter().flatten() {
+            self.output.extend(worker.output);
+            self.source_map.append(worker.source_map);
+            self.saved_instructions += worker.saved_instructions;
+            self.cached_instructions += worker.cached_instructions;
+        }
+        Ok(self.output.join("\n") + "\n")
     }
 
-    fn emit_boot(&mut self) {
-        self.output.append(
-            vec![
-                "@256".to_string(),
-                "D=A".to_string(),
-                "@SP".to_string(),
-                "M=D".to_string(),
-            ]
-            .as_mut(),
+    /// a translator for the file `filename` with the settings of this one.
+    fn worker(&self, filename: String) -> VMTranslator {
+        VMTranslator {
+            filename,
+            compact: self.compact,
+            optimize: self.optimize,
+            annotate: self.annotate,
+            fast_compare: self.fast_compare,
+            extended_isa: self.extended_isa,
+            stack_check: self.stack_check,
+            cache_top: self.cache_top,
+            reachable: self.reachable.clone(),
+            ..Self::new()
+        }
+    }
+
+    fn find_reachable(&mut self, files: &[(String, Vec<(usize, vm::Command)>)]) {
+        let files = files
+            .iter()
+            .map(|(name, commands)| (name.as_str(), commands.as_slice()))
+            .collect::<Vec<_>>();
+        let graph = CallGraph::new(&files);
+        if !graph.functions.contains_key("Sys.init") {
+            return;
+        }
+        let reachable = graph.reachable("Sys.init");
+        self.removed_functions = graph
+            .functions
+            .keys()
+            .filter(|name| !reachable.contains(*name))
+            .cloned()
+            .collect();
+        self.reachable = Some(reachable);
+    }
+
+    /// false for commands inside a function removed by tree shaking, `function` is
+    /// the name of the function being declared by the command, if any.
+    fn keep(&self, kept: &mut bool, function: Option<&str>) -> bool {
+        if let (Some(reachable), Some(function)) = (&self.reachable, function) {
+            *kept = reachable.contains(function);
+        }
+        *kept
+    }
+
+    fn emit_boot(&mut self) -> Result<(), String> {
+        // labels of their own, no jack class can be named like that
+        self.filename = "$boot".to_string();
+        if self.annotate {
+            self.emit("// bootstrap");
+        }
+        self.emit(
+            "@256\n\
+             D=A\n\
+             @SP\n\
+             M=D",
         );
-        self.translate_line("call Sys.init 0");
+        self.translate_line("call Sys.init 0")
+    }
+
+    fn process_single(&mut self, commands: Vec<(usize, vm::Command)>) -> Result<(), String> {
+        let file = format!("{}.vm", self.filename);
+        self.origin = Some(Mapping {
+            file: self.source_map.add_file(&file),
+            line: 0,
+            function: None,
+        });
+        self.process_commands(commands)
+    }
+
+    fn process_commands(&mut self, commands: Vec<(usize, vm::Command)>) -> Result<(), String> {
+        let mut kept = true;
+        let commands = commands
+            .into_iter()
+            .filter(|(_, command)| match command {
+                vm::Command::Function(name, _) => self.keep(&mut kept, Some(name)),
+                _ => self.keep(&mut kept, None),
+            })
+            .collect::<Vec<_>>();
+        if !self.optimize && !self.cache_top {
+            for (number, command) in commands {
+                let line = command.to_string();
+                self.begin_command(number, &line);
+                self.translate_command(&line, &command)
+                    .map_err(|e| format!("{}.vm line {}: {}", self.filename, number, e))?;
+            }
+            return Ok(());
+        }
+
+        // translate once without optimizing to know how much we saved, and once more
+        // after the peephole optimizer to tell its savings from the cached top's
+        let baseline = self.count_uncached(&commands)?;
+        let commands = match self.optimize {
+            true => optimizer::peephole(commands),
+            false => commands,
+        };
+        let optimized = match self.optimize && self.cache_top {
+            true => self.count_uncached(&commands)?,
+            false => baseline,
+        };
+        let start = self.output.len();
+        for (number, command) in commands {
+            let line = command.to_string();
+            self.begin_command(number, &line);
+            self.translate_command(&line, &command)
+                .map_err(|e| format!("{}.vm line {}: {}", self.filename, number, e))?;
+        }
+        self.flush_top();
+        // the stack check writes the cached top back after every push, which can cost more
+        let count = instruction_count(&self.output[start..]);
+        match self.cache_top {
+            true => {
+                self.saved_instructions += baseline.saturating_sub(optimized);
+                self.cached_instructions += optimized.saturating_sub(count);
+            }
+            false => self.saved_instructions += baseline.saturating_sub(count),
+        }
+        Ok(())
+    }
+
+    /// the number of instructions `commands` translate to without caching the top
+    /// of the stack, leaving no trace in the output.
+    fn count_uncached(&mut self, commands: &[(usize, vm::Command)]) -> Result<usize, String> {
+        let (start, label_index) = (self.output.len(), self.label_index);
+        let (start_address, next_counter) = (self.source_map.len(), self.next_counter);
+        let cache_top = std::mem::replace(&mut self.cache_top, false);
+        let translated = commands.iter().try_for_each(|(number, command)| {
+            self.translate_command(&command.to_string(), command)
+                .map_err(|e| format!("{}.vm line {}: {}", self.filename, number, e))
+        });
+        let count = instruction_count(&self.output[start..]);
+        self.output.truncate(start);
+        self.source_map.truncate(start_address);
+        self.label_index = label_index;
+        self.next_counter = next_counter;
+        self.cache_top = cache_top;
+        translated.map(|_| count)
+    }
+
+    /// translate `command`, counting function entries and calls when profiling and
+    /// checking the stack bounds when asked to.
+    fn translate_command(&mut self, line: &str, command: &vm::Command) -> Result<(), String> {
+        if let vm::Command::Call(..) = command {
+            self.flush_top();
+            self.emit_counter();
+        }
+        match command {
+            vm::Command::Shr if self.extended_isa => {}
+            vm::Command::Div | vm::Command::Shr => self.emit_stack_check(scratch_words(command)),
+            _ => {}
+        }
+        if !self.cache_top || !self.translate_cached(command)? {
+            self.flush_top();
+            self.translate_line(line)?;
+        }
+        if let vm::Command::Function(..) = command {
+            self.emit_counter();
+        }
+        if let vm::Command::Push(..) | vm::Command::Function(..) = command {
+            self.emit_stack_check(0);
+        }
+        Ok(())
     }
 
-    fn process_single(&mut self, path: PathBuf) {
-        let vm_code = std::fs::read_to_string(path).expect("cannot read file");
-        for raw_line in vm_code.lines() {
-            if let Some(before_comment) = raw_line.split("//").next() {
-                let line = before_comment.trim();
-                if line.is_empty() {
-                    continue;
+    /// jump to the halt routine unless `STACK_START <= SP + scratch < STACK_END`. at a
+    /// function entry this covers the frame pushed by `call` and the locals, before
+    /// `div` and `shr` the scratch words of their routines.
+    fn emit_stack_check(&mut self, scratch: usize) {
+        if !self.stack_check {
+            return;
+        }
+        self.flush_top();
+        self.emit(&format!(
+            "@SP\n\
+             D=M\n\
+             @{}\n\
+             D=D-A\n\
+             @$$STACK_OVERFLOW\n\
+             D;JGE\n\
+             @{}\n\
+             D=D+A\n\
+             @$$STACK_UNDERFLOW\n\
+             D;JLT",
+            STACK_END - scratch,
+            STACK_END - scratch - STACK_START
+        ));
+    }
+
+    /// translate `command` with the top of the stack in D where that saves work,
+    /// false for commands that need the whole stack in memory.
+    fn translate_cached(&mut self, command: &vm::Command) -> Result<bool, String> {
+        use vm::{Command, Segment};
+        match command {
+            Command::Push(Segment::Constant, i) => {
+                self.flush_top();
+                match i {
+                    0 | 1 => self.emit(&format!("D={}", i)),
+                    _ => self.emit(&format!("@{}\nD=A", i)),
+                }
+                self.top_in_d = true;
+            }
+            Command::Push(segment, i) => {
+                self.flush_top();
+                self.load_value(segment.as_str(), &i.to_string())?;
+                self.top_in_d = true;
+            }
+            Command::Pop(Segment::Constant, _) => return Ok(false),
+            Command::Pop(segment, i) => {
+                self.fill_top();
+                self.store_top(*segment, *i)?;
+            }
+            Command::Add => self.operate_cached("D=D+M"),
+            Command::Sub => self.operate_cached("D=M-D"),
+            Command::And => self.operate_cached("D=D&M"),
+            Command::Or => self.operate_cached("D=D|M"),
+            Command::Eq if !self.compact => self.compare_cached("JEQ"),
+            Command::Gt if self.fast_compare && !self.compact => self.compare_cached("JGT"),
+            Command::Lt if self.fast_compare && !self.compact => self.compare_cached("JLT"),
+            Command::Neg => self.unary_cached("D=-D"),
+            Command::Not => self.unary_cached("D=!D"),
+            Command::Inc => self.unary_cached("D=D+1"),
+            Command::Shl if self.extended_isa => self.unary_cached("D=D<<"),
+            Command::Shl => self.unary_cached("@R13\nM=D\nD=D+M"),
+            Command::IfGoto(label) | Command::IfNotGoto(label) => {
+                self.fill_top();
+                if let Command::IfNotGoto(_) = command {
+                    self.emit("D=D+1");
                 }
-                self.translate_line(line)
+                self.emit(&format!("@{}\nD;JNE", self.scoped(label)));
+                self.top_in_d = false;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// write a top of the stack held in D back to the stack.
+    fn flush_top(&mut self) {
+        if !self.top_in_d {
+            return;
+        }
+        self.emit(
+            "@SP\n\
+             A=M\n\
+             M=D",
+        );
+        self.incr_sp();
+        self.top_in_d = false;
+    }
+
+    /// pop the top of the stack into D unless it is there already.
+    fn fill_top(&mut self) {
+        if self.top_in_d {
+            return;
+        }
+        self.emit(
+            "@SP\n\
+             AM=M-1\n\
+             D=M",
+        );
+        self.top_in_d = true;
+    }
+
+    /// store D, the popped top of the stack, in segment[i].
+    fn store_top(&mut self, segment: vm::Segment, i: u16) -> Result<(), String> {
+        self.top_in_d = false;
+        let location = i.to_string();
+        if let Some(addr) = self.direct_addr(segment.as_str(), &location) {
+            self.emit(&format!("{}\nM=D", addr));
+            return Ok(());
+        }
+        let pointer = match segment {
+            vm::Segment::Local => "LCL",
+            vm::Segment::Argument => "ARG",
+            vm::Segment::This => "THIS",
+            vm::Segment::That => "THAT",
+            _ => return Err(format!("{} {} is not addressable", segment.as_str(), i)),
+        };
+        // stepping A up is shorter than computing the address for small offsets
+        if i <= 8 {
+            self.emit(&format!(
+                "@{}\nA=M{}\nM=D",
+                pointer,
+                "\nA=A+1".repeat(i as usize)
+            ));
+            return Ok(());
+        }
+        self.emit(&format!(
+            "@R13\n\
+             M=D\n\
+             @{}\n\
+             D=A\n\
+             @{}\n\
+             D=D+M\n\
+             @R14\n\
+             M=D\n\
+             @R13\n\
+             D=M\n\
+             @R14\n\
+             A=M\n\
+             M=D",
+            i, pointer
+        ));
+        Ok(())
+    }
+
+    /// D = x op D with y in D, x popped from the stack.
+    fn operate_cached(&mut self, op: &str) {
+        self.fill_top();
+        self.emit(&format!("@SP\nAM=M-1\n{}", op));
+    }
+
+    fn unary_cached(&mut self, op: &str) {
+        self.fill_top();
+        self.emit(op);
+    }
+
+    /// D = x - y compared against 0 with `condition`. only exact for `JEQ`, or with
+    /// `fast_compare`.
+    fn compare_cached(&mut self, condition: &str) {
+        self.operate_cached("D=M-D");
+        let label = self.unique_label();
+        self.emit(&format!(
+            "@TRUE_{0}\n\
+             D;{1}\n\
+             D=0\n\
+             @END_{0}\n\
+             0;JMP\n\
+             (TRUE_{0})\n\
+             D=-1\n\
+             (END_{0})",
+            label, condition
+        ));
+    }
+
+    /// increment the next 32 bit counter, the high word when the low one wraps.
+    fn emit_counter(&mut self) {
+        let Some(address) = self.next_counter else {
+            return;
+        };
+        self.next_counter = Some(address + 2);
+        self.emit(&format!(
+            "@{0}\n\
+             M=M+1\n\
+             D=M\n\
+             @$$PROFILE.{0}\n\
+             D;JNE\n\
+             @{1}\n\
+             M=M+1\n\
+             ($$PROFILE.{0})",
+            address,
+            address + 1
+        ));
+    }
+
+    /// record where the following code comes from and annotate it if asked to.
+    fn begin_command(&mut self, number: usize, line: &str) {
+        let function_name = function_name(line);
+        if let Some(origin) = self.origin.as_mut() {
+            origin.line = number;
+            if let Some(function_name) = function_name {
+                origin.function = Some(self.source_map.add_function(function_name));
             }
         }
+        if !self.annotate {
+            return;
+        }
+        if let Some(function_name) = function_name {
+            let rule = "/".repeat(60);
+            self.emit(&format!(
+                "{0}\n\
+                 // function {1}\n\
+                 {0}",
+                rule, function_name
+            ));
+        }
+        let filename = self.filename.clone();
+        self.emit(&format!("// {}.vm:{}: {}", filename, number, line));
     }
 
     fn emit(&mut self, code: &str) {
+        self.source_map.push(self.origin, instructions_in(code));
         self.output.push(code.to_string())
     }
 
@@ -93,21 +779,12 @@ impl VMTranslator {
         self.incr_sp();
     }
 
-    fn translate_line(&mut self, line: &str) {
+    fn translate_line(&mut self, line: &str) -> Result<(), String> {
         let parts: Vec<&str> = line.split(' ').collect();
         // println!("parts: {:?}", parts);
-        match (*parts.get(0).unwrap(), parts.get(1), parts.get(2)) {
+        match (parts[0], parts.get(1), parts.get(2)) {
             ("push", Some(&segment), Some(location)) if location.parse::<u16>().is_ok() => {
-                if segment == "constant" {
-                    self.emit(&format!(
-                        "@{}\n\
-                         D=A",
-                        location
-                    ));
-                } else {
-                    self.select_target_addr(segment, location);
-                    self.emit("D=M");
-                };
+                self.load_value(segment, location)?;
                 self.emit(
                     "@SP\n\
                           A=M\n\
@@ -117,7 +794,7 @@ impl VMTranslator {
             }
             ("pop", Some(&segment), Some(location)) if location.parse::<u16>().is_ok() => {
                 self.decr_sp();
-                self.select_target_addr(segment, location);
+                self.select_target_addr(segment, location)?;
                 self.emit(
                     "D=A\n\
                           @SP\n\
@@ -129,10 +806,52 @@ impl VMTranslator {
                           M=D-A",
                 );
             }
+            ("move", Some(&from_segment), Some(from_location)) => {
+                let (to_segment, to_location) = match (parts.get(3), parts.get(4)) {
+                    (Some(segment), Some(location)) => (*segment, *location),
+                    _ => return Err(format!("missing operand in `{}`", line)),
+                };
+                let direct_addr = self.direct_addr(to_segment, to_location);
+                if direct_addr.is_none() {
+                    self.select_target_addr(to_segment, to_location)?;
+                    self.emit(
+                        "D=A\n\
+                         @R13\n\
+                         M=D",
+                    );
+                }
+                self.load_value(from_segment, from_location)?;
+                match direct_addr {
+                    Some(addr) => self.emit(&format!("{}\nM=D", addr)),
+                    None => self.emit(
+                        "@R13\n\
+                         A=M\n\
+                         M=D",
+                    ),
+                }
+            }
             ("call", Some(&function_name), Some(n_args)) => {
-                let n = n_args.parse::<u16>().unwrap();
-                let return_label = format!("{}$ret.{}", function_name, self.label_index);
-                self.label_index += 1;
+                let n = parse_number(n_args, line)?;
+                let return_label = format!("{}$ret.{}", function_name, self.unique_label());
+                if self.compact {
+                    self.emit(&format!(
+                        "@{}\n\
+                         D=A\n\
+                         @R13\n\
+                         M=D\n\
+                         @{}\n\
+                         D=A\n\
+                         @R14\n\
+                         M=D\n\
+                         @{2}\n\
+                         D=A\n\
+                         @$$CALL\n\
+                         0;JMP\n\
+                         ({2})",
+                        n, function_name, return_label
+                    ));
+                    return Ok(());
+                }
                 self.emit(&format!(
                     "@{}\n\
                      D=A\n\
@@ -166,11 +885,12 @@ impl VMTranslator {
                 ));
             }
             ("function", Some(&function_name), Some(n_args)) => {
+                self.function = function_name.to_string();
                 self.emit(&format!("({})", function_name,));
-                let n = n_args.parse::<u16>().unwrap();
+                let n = parse_number(n_args, line)?;
                 (0..n).for_each(|_| {
                     self.emit(
-                        "@0
+                        "@0\n\
                      D=A\n\
                      @SP\n\
                      A=M\n\
@@ -180,11 +900,11 @@ impl VMTranslator {
                 });
             }
             (move_cmd, Some(&target), None) => match move_cmd {
-                "label" => self.emit(&format!("({})", target)),
+                "label" => self.emit(&format!("({})", self.scoped(target))),
                 "goto" => self.emit(&format!(
                     "@{}\n\
                      0;JMP",
-                    target
+                    self.scoped(target)
                 )),
                 "if-goto" => {
                     self.decr_sp();
@@ -194,33 +914,56 @@ impl VMTranslator {
                          D=M\n\
                          @{}\n\
                          D;JNE",
-                        target
+                        self.scoped(target)
                     ))
                 }
-                _ => unimplemented!(),
+                // `not` is -1 for nothing but 0
+                "if-not-goto" => self.emit(&format!(
+                    "@SP\n\
+                     AM=M-1\n\
+                     D=M+1\n\
+                     @{}\n\
+                     D;JNE",
+                    self.scoped(target)
+                )),
+                _ => return Err(format!("cannot translate `{}`", line)),
             },
             (op, None, None) => match op {
                 "add" => self.operate_top_two("M=M+D"),
                 "sub" => self.operate_top_two("M=M-D"),
                 "neg" => self.operate_top("M=-M"),
+                "eq" | "gt" | "lt" if self.compact => self.jump_to_routine(&op.to_uppercase()),
                 "eq" => {
                     self.operate_top_two("D=M-D");
                     self.emit_logical_commands("JEQ");
                 }
-                "gt" => {
+                "gt" if self.fast_compare => {
                     self.operate_top_two("D=M-D");
                     self.emit_logical_commands("JGT");
                 }
-                "lt" => {
+                "lt" if self.fast_compare => {
                     self.operate_top_two("D=M-D");
                     self.emit_logical_commands("JLT");
                 }
+                "gt" => self.emit_signed_compare("JGT"),
+                "lt" => self.emit_signed_compare("JLT"),
                 "and" => self.operate_top_two("M=M&D"),
                 "or" => self.operate_top_two("M=M|D"),
                 "not" => self.operate_top("M=!M"),
+                "inc" => self.operate_top("M=M+1"),
+                "mul" => self.jump_to_routine("MUL"),
+                "div" => self.jump_to_routine("DIV"),
+                "shl" if self.extended_isa => self.operate_top("M=M<<"),
+                "shl" => self.operate_top("D=M\nM=D+M"),
+                "shr" if self.extended_isa => self.operate_top("M=M>>"),
+                "shr" => self.jump_to_routine("SHR"),
+                "return" if self.compact => self.emit(
+                    "@$$RETURN\n\
+                     0;JMP",
+                ),
                 "return" => {
                     self.decr_sp();
-                    self.emit(&format!(
+                    self.emit(
                         "@LCL\n\
                          D=M\n\
                          @5\n\
@@ -266,25 +1009,26 @@ impl VMTranslator {
                          A=M-D\n\
                          D=M\n\
                          @LCL\n\
-                         M=D"
-                    ));
+                         M=D",
+                    );
 
                     // goto ret addr
-                    self.emit(&format!(
+                    self.emit(
                         "@R13\n\
                          A=M\n\
-                         0;JMP"
-                    ));
+                         0;JMP",
+                    );
                 }
-                _ => unimplemented!(),
+                _ => return Err(format!("cannot translate `{}`", line)),
             },
-            _ => unimplemented!(),
+            _ => return Err(format!("cannot translate `{}`", line)),
         };
+        Ok(())
     }
 
-    fn select_target_addr<'a>(&mut self, segment: &str, location: &'a str) {
+    fn select_target_addr(&mut self, segment: &str, location: &str) -> Result<(), String> {
         let update_cmd = match segment {
-            "static" => format!("@{}.{}", self.filename.to_string_lossy(), location),
+            "static" => format!("@{}", static_symbol(&self.filename, location)),
             "temp" => format!(
                 "@5\n\
                  D=A\n\
@@ -296,7 +1040,7 @@ impl VMTranslator {
                 let label = match location {
                     "0" => "THIS",
                     "1" => "THAT",
-                    _ => unreachable!(),
+                    _ => return Err(format!("pointer {} is out of range", location)),
                 };
                 format!("@{}", label)
             }
@@ -306,7 +1050,7 @@ impl VMTranslator {
                     "argument" => "ARG",
                     "this" => "THIS",
                     "that" => "THAT",
-                    _ => unreachable!(),
+                    _ => return Err(format!("{} {} is not addressable", segment, location)),
                 };
                 format!(
                     "@{}\n\
@@ -317,10 +1061,39 @@ impl VMTranslator {
                 )
             }
         };
-        self.emit(&update_cmd)
+        self.emit(&update_cmd);
+        Ok(())
+    }
+
+    /// make D = value of segment[location]
+    fn load_value(&mut self, segment: &str, location: &str) -> Result<(), String> {
+        if segment == "constant" {
+            self.emit(&format!(
+                "@{}\n\
+                 D=A",
+                location
+            ));
+        } else {
+            self.select_target_addr(segment, location)?;
+            self.emit("D=M");
+        };
+        Ok(())
+    }
+
+    /// the address of segment[location] as a single A instruction, when it is known
+    /// without touching D.
+    fn direct_addr(&self, segment: &str, location: &str) -> Option<String> {
+        match (segment, location) {
+            ("static", _) => Some(format!("@{}", static_symbol(&self.filename, location))),
+            ("temp", _) => Some(format!("@{}", 5 + location.parse::<u16>().ok()?)),
+            ("pointer", "0") => Some("@THIS".to_string()),
+            ("pointer", "1") => Some("@THAT".to_string()),
+            _ => None,
+        }
     }
 
     fn emit_logical_commands(&mut self, condition: &str) {
+        let label = self.unique_label();
         self.emit(&format!(
             "@IF_{0}\n\
              D;{1}\n\
@@ -337,61 +1110,600 @@ impl VMTranslator {
                  A=M-1\n\
                  M=0\n\
              (END_{0})",
-            self.label_index, condition
+            label, condition
         ));
-        self.label_index += 1;
     }
 
-    /// make M = x; D = y; SP--
-    fn operate_top_two(&mut self, op_code: &str) {
-        self.emit(
-            "@SP\n\
-                  M=M-1\n\
-                  A=M\n\
-                  D=M\n\
-                  @SP\n\
-                  A=M-1",
-        );
-        self.emit(op_code)
+    /// `gt` / `lt` that only subtract operands of the same sign, which cannot
+    /// overflow. otherwise the sign of x alone decides.
+    fn emit_signed_compare(&mut self, condition: &str) {
+        let prefix = format!("CMP_{}", self.unique_label());
+        self.emit(&signed_compare(&prefix, condition, "R13"));
     }
 
-    fn operate_top(&mut self, op_code: &str) {
-        self.emit("@SP\nA=M-1");
-        self.emit(op_code)
+    /// call a shared routine like `$$EQ` with D = return address.
+    fn jump_to_routine(&mut self, routine: &str) {
+        let label = self.unique_label();
+        self.emit(&format!(
+            "@$$RET.{0}\n\
+             D=A\n\
+             @$${1}\n\
+             0;JMP\n\
+             ($$RET.{0})",
+            label, routine
+        ));
     }
 
-    fn incr_sp(&mut self) {
+    /// emit the `$$MUL`, `$$DIV` and `$$SHR` routines in `routines`, skipped over
+    /// like the compact mode routines. they expect D = return address and replace
+    /// the operands on the stack with the result, the words above SP are scratch.
+    fn emit_arithmetic_routines(&mut self, routines: &BTreeSet<&str>) {
         self.emit(
-            "@SP\n\
-                  M=M+1",
-        )
+            "@$$ARITHMETIC.END\n\
+             0;JMP",
+        );
+        for routine in routines {
+            self.emit(match *routine {
+                "MUL" => MUL_ROUTINE,
+                "DIV" => DIV_ROUTINE,
+                _ => SHR_ROUTINE,
+            });
+        }
+        self.emit("($$ARITHMETIC.END)");
     }
-    fn decr_sp(&mut self) {
-        self.emit(
+
+    /// emit the routine `emit_stack_check` jumps to, skipped over like the compact
+    /// mode routines. it stores the error code in `RAM[STACK_ERROR]` and spins.
+    fn emit_stack_halt(&mut self) {
+        self.emit(&format!(
+            "@$$STACK_HALT.END\n\
+             0;JMP\n\
+             ($$STACK_OVERFLOW)\n\
+             @{}\n\
+             D=A\n\
+             @$$STACK_HALT\n\
+             0;JMP\n\
+             ($$STACK_UNDERFLOW)\n\
+             @{}\n\
+             D=A\n\
+             ($$STACK_HALT)\n\
+             @{}\n\
+             M=D\n\
+             ($$STACK_HALT.LOOP)\n\
+             @$$STACK_HALT.LOOP\n\
+             0;JMP\n\
+             ($$STACK_HALT.END)",
+            STACK_OVERFLOW, STACK_UNDERFLOW, STACK_ERROR
+        ));
+    }
+
+    /// the assembler name of the vm label `label`, labels of different functions
+    /// may share a name.
+    fn scoped(&self, label: &str) -> String {
+        match self.function.as_str() {
+            "" => label.to_string(),
+            function => format!("{}${}", function, label),
+        }
+    }
+
+    /// a label suffix used by no other site of the program. labels are numbered
+    /// per file so that files can be translated independently.
+    fn unique_label(&mut self) -> String {
+        let label = format!("{}.{}", self.filename, self.label_index);
+        self.label_index += 1;
+        label
+    }
+
+    /// emit the routines used by compact mode, guarded by a jump so that
+    /// execution starting at ROM 0 skips over them.
+    ///
+    /// `$$CALL` expects R13 = nArgs, R14 = callee address and D = return address.
+    /// `$$EQ/$$GT/$$LT` expect D = return address.
+    fn emit_shared_routines(&mut self) {
+        self.emit(
+            "@$$START\n\
+             0;JMP",
+        );
+        self.emit(
+            "($$CALL)\n\
+             @SP\n\
+             A=M\n\
+             M=D",
+        );
+        for pointer in &["LCL", "ARG", "THIS", "THAT"] {
+            self.emit(&format!(
+                "@{}\n\
+                 D=M\n\
+                 @SP\n\
+                 AM=M+1\n\
+                 M=D",
+                pointer
+            ));
+        }
+        self.emit(
+            "@SP\n\
+             MD=M+1\n\
+             @LCL\n\
+             M=D\n\
+             @5\n\
+             D=D-A\n\
+             @R13\n\
+             D=D-M\n\
+             @ARG\n\
+             M=D\n\
+             @R14\n\
+             A=M\n\
+             0;JMP",
+        );
+        self.emit(
+            "($$RETURN)\n\
+             @LCL\n\
+             D=M\n\
+             @R13\n\
+             M=D\n\
+             @5\n\
+             A=D-A\n\
+             D=M\n\
+             @R14\n\
+             M=D\n\
+             @SP\n\
+             AM=M-1\n\
+             D=M\n\
+             @ARG\n\
+             A=M\n\
+             M=D\n\
+             @ARG\n\
+             D=M+1\n\
+             @SP\n\
+             M=D",
+        );
+        for pointer in &["THAT", "THIS", "ARG", "LCL"] {
+            self.emit(&format!(
+                "@R13\n\
+                 AM=M-1\n\
+                 D=M\n\
+                 @{}\n\
+                 M=D",
+                pointer
+            ));
+        }
+        self.emit(
+            "@R14\n\
+             A=M\n\
+             0;JMP",
+        );
+        for (op, condition) in &[("EQ", "JEQ"), ("GT", "JGT"), ("LT", "JLT")] {
+            let compare = if *op == "EQ" || self.fast_compare {
+                format!(
+                    "@SP\n\
+                     AM=M-1\n\
+                     D=M\n\
+                     A=A-1\n\
+                     D=M-D\n\
+                     M=-1\n\
+                     @$${0}_END\n\
+                     D;{1}\n\
+                     @SP\n\
+                     A=M-1\n\
+                     M=0\n\
+                     ($${0}_END)",
+                    op, condition
+                )
+            } else {
+                signed_compare(&format!("$${}", op), condition, "R14")
+            };
+            self.emit(&format!(
+                "($${0})\n\
+                 @R13\n\
+                 M=D\n\
+                 {1}\n\
+                 @R13\n\
+                 A=M\n\
+                 0;JMP",
+                op, compare
+            ));
+        }
+        self.emit("($$START)");
+    }
+
+    /// make M = x; D = y; SP--
+    fn operate_top_two(&mut self, op_code: &str) {
+        self.emit(
+            "@SP\n\
+                  M=M-1\n\
+                  A=M\n\
+                  D=M\n\
+                  @SP\n\
+                  A=M-1",
+        );
+        self.emit(op_code)
+    }
+
+    fn operate_top(&mut self, op_code: &str) {
+        self.emit("@SP\nA=M-1");
+        self.emit(op_code)
+    }
+
+    fn incr_sp(&mut self) {
+        self.emit(
+            "@SP\n\
+                  M=M+1",
+        )
+    }
+    fn decr_sp(&mut self) {
+        self.emit(
             "@SP\n\
                   M=M-1",
         )
     }
 }
 
+/// shift and add, x doubles while the set bits of y are cleared one by one.
+/// R14 = y, R15 = x, RAM[SP] = bit of y, the product adds up where x was.
+const MUL_ROUTINE: &str = "($$MUL)
+@R13
+M=D
+@SP
+AM=M-1
+D=M
+@R14
+M=D
+@SP
+A=M-1
+D=M
+@R15
+M=D
+@SP
+A=M-1
+M=0
+@SP
+A=M
+M=1
+($$MUL.LOOP)
+@R14
+D=M
+@$$MUL.END
+D;JEQ
+@SP
+A=M
+D=D&M
+@$$MUL.NEXT
+D;JEQ
+@SP
+A=M
+D=M
+@R14
+M=M-D
+@R15
+D=M
+@SP
+A=M-1
+M=M+D
+($$MUL.NEXT)
+@R15
+D=M
+M=D+M
+@SP
+A=M
+D=M
+M=D+M
+@$$MUL.LOOP
+0;JMP
+($$MUL.END)
+@R13
+A=M
+0;JMP";
+
+/// long division of |x| by |y| as unsigned 16 bit numbers, one bit of x per
+/// round. R14 = |y|, R15 = the bits of |x| left, RAM[SP] = remainder,
+/// RAM[SP + 1] = negate the quotient, RAM[SP + 2] = rounds left.
+const DIV_ROUTINE: &str = "($$DIV)
+@R13
+M=D
+@SP
+AM=M-1
+D=M
+@R14
+M=D
+@SP
+A=M-1
+D=M
+@R15
+M=D
+@SP
+A=M-1
+M=0
+@R14
+D=M
+@$$DIV.END
+D;JEQ
+@SP
+A=M+1
+M=0
+@R15
+D=M
+@$$DIV.X_POSITIVE
+D;JGE
+@R15
+M=-M
+@SP
+A=M+1
+M=!M
+($$DIV.X_POSITIVE)
+@R14
+D=M
+@$$DIV.Y_POSITIVE
+D;JGE
+@R14
+M=-M
+@SP
+A=M+1
+M=!M
+($$DIV.Y_POSITIVE)
+@SP
+A=M
+M=0
+@16
+D=A
+@SP
+A=M+1
+A=A+1
+M=D
+($$DIV.LOOP)
+@SP
+A=M
+D=M
+M=D+M
+@R15
+D=M
+@$$DIV.SHIFT
+D;JGE
+@SP
+A=M
+M=M+1
+($$DIV.SHIFT)
+@R15
+D=M
+M=D+M
+@SP
+A=M-1
+D=M
+M=D+M
+@SP
+A=M
+D=M
+@$$DIV.SUBTRACT
+D;JLT
+@R14
+D=M
+@$$DIV.NEXT
+D;JLT
+@SP
+A=M
+D=M
+@R14
+D=D-M
+@$$DIV.NEXT
+D;JLT
+($$DIV.SUBTRACT)
+@R14
+D=M
+@SP
+A=M
+M=M-D
+@SP
+A=M-1
+M=M+1
+($$DIV.NEXT)
+@SP
+A=M+1
+A=A+1
+MD=M-1
+@$$DIV.LOOP
+D;JGT
+@SP
+A=M+1
+D=M
+@$$DIV.END
+D;JEQ
+@SP
+A=M-1
+M=-M
+($$DIV.END)
+@R13
+A=M
+0;JMP";
+
+/// copy every bit of x but the lowest one bit down, then the sign bit.
+/// R14 = x, R15 = bit of x, RAM[SP] = bit of the result.
+const SHR_ROUTINE: &str = "($$SHR)
+@R13
+M=D
+@SP
+A=M-1
+D=M
+@R14
+M=D
+@SP
+A=M-1
+M=0
+@2
+D=A
+@R15
+M=D
+@SP
+A=M
+M=1
+($$SHR.LOOP)
+@R14
+D=M
+@R15
+D=D&M
+@$$SHR.NEXT
+D;JEQ
+@SP
+A=M
+D=M
+@SP
+A=M-1
+M=M|D
+($$SHR.NEXT)
+@SP
+A=M
+D=M
+M=D+M
+@R15
+D=M
+MD=D+M
+@$$SHR.LOOP
+D;JNE
+@R14
+D=M
+@$$SHR.END
+D;JGE
+@32767
+D=!A
+@SP
+A=M-1
+M=M|D
+($$SHR.END)
+@R13
+A=M
+0;JMP";
+
+/// replace x, y on top of the stack with x > y (`JGT`) or x < y (`JLT`), keeping y
+/// in `scratch`. x and y of different signs are ordered by their signs alone.
+fn signed_compare(prefix: &str, condition: &str, scratch: &str) -> String {
+    // the answer when x >= 0 > y, and the opposite when x < 0 <= y
+    let (x_positive, x_negative) = if condition == "JGT" {
+        ("TRUE", "FALSE")
+    } else {
+        ("FALSE", "TRUE")
+    };
+    format!(
+        "@SP\n\
+         AM=M-1\n\
+         D=M\n\
+         @{2}\n\
+         M=D\n\
+         @SP\n\
+         A=M-1\n\
+         D=M\n\
+         @{0}_X_NEGATIVE\n\
+         D;JLT\n\
+         @{2}\n\
+         D=M\n\
+         @{0}_SAME_SIGN\n\
+         D;JGE\n\
+         @{0}_{3}\n\
+         0;JMP\n\
+         ({0}_X_NEGATIVE)\n\
+         @{2}\n\
+         D=M\n\
+         @{0}_SAME_SIGN\n\
+         D;JLT\n\
+         @{0}_{4}\n\
+         0;JMP\n\
+         ({0}_SAME_SIGN)\n\
+         @{2}\n\
+         D=M\n\
+         @SP\n\
+         A=M-1\n\
+         D=M-D\n\
+         @{0}_TRUE\n\
+         D;{1}\n\
+         ({0}_FALSE)\n\
+         @SP\n\
+         A=M-1\n\
+         M=0\n\
+         @{0}_END\n\
+         0;JMP\n\
+         ({0}_TRUE)\n\
+         @SP\n\
+         A=M-1\n\
+         M=-1\n\
+         ({0}_END)",
+        prefix, condition, scratch, x_positive, x_negative
+    )
+}
+
+/// `text` as a vm operand number.
+fn parse_number(text: &str, line: &str) -> Result<u16, String> {
+    text.parse::<u16>()
+        .map_err(|_| format!("invalid number {} in `{}`", text, line))
+}
+
+/// the function declared by a `function f n` line.
+fn function_name(line: &str) -> Option<&str> {
+    line.strip_prefix("function ")
+        .map(|rest| rest.split_whitespace().next().unwrap_or(""))
+}
+
+/// the symbol of `static i` in a file. the assembler gives every such symbol the next
+/// free address from 16 on, in order of first use, and so does the x86 backend.
+pub(crate) fn static_symbol(file: &str, index: impl std::fmt::Display) -> String {
+    format!("{}.{}", file, index)
+}
+
+fn write_file(path: &std::path::Path, content: &str) -> Result<(), String> {
+    std::fs::write(path, content).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// count real instructions, skipping labels and comments.
+fn instruction_count(output: &[String]) -> usize {
+    output.iter().map(|code| instructions_in(code)).sum()
+}
+
+fn instructions_in(code: &str) -> usize {
+    code.lines()
+        .map(|line| line.split("//").next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('('))
+        .count()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::translator::VMTranslator;
+    use crate::translator::{instruction_count, VMTranslator};
     use std::ffi::OsStr;
     use std::path::PathBuf;
 
     fn translate_and_run(name: &str) {
         let mut vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../projects/");
         vm_path.push(OsStr::new(name));
+        run_in(vm_path.canonicalize().unwrap(), |_| {});
+    }
+
+    /// translate a copy of the project so that variants can run alongside the default tests
+    fn translate_variant_and_run(name: &str, variant: &str, configure: impl Fn(&mut VMTranslator)) {
+        let mut vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../projects/");
+        vm_path.push(OsStr::new(name));
+        let copy_path = std::env::temp_dir()
+            .join(format!("nand2tetris-{}", variant))
+            .join(name);
+        std::fs::create_dir_all(&copy_path).expect("failed to create dir");
+        for entry in std::fs::read_dir(&vm_path).expect("failed to read dir") {
+            let path = entry.unwrap().path();
+            if path.is_file() {
+                std::fs::copy(&path, copy_path.join(path.file_name().unwrap()))
+                    .expect("failed to copy file");
+            }
+        }
+        run_in(copy_path, configure);
+    }
 
+    fn run_in(vm_path: PathBuf, configure: impl Fn(&mut VMTranslator)) {
         let filename = vm_path.file_name().unwrap().to_os_string();
         let mut tst_path = vm_path.clone();
         tst_path.push(&filename);
         tst_path.set_extension("tst");
 
-        VMTranslator::load(vm_path.canonicalize().unwrap())
-            .process()
-            .write();
+        let mut translator = VMTranslator::load(vm_path);
+        configure(&mut translator);
+        translator.process().unwrap().write().unwrap();
+        run_emulator(&tst_path);
+    }
+
+    fn run_emulator(tst_path: &std::path::Path) {
         let output = std::process::Command::new("sh")
             .arg(concat!(
                 env!("CARGO_MANIFEST_DIR"),
@@ -402,10 +1714,504 @@ mod tests {
             .expect("failed to execute process");
         if !output.status.success() {
             println!("error: {:?}", output);
-            panic!(output.stderr)
+            panic!("{}", String::from_utf8_lossy(&output.stderr))
+        }
+    }
+
+    /// run `vm_code` as a single file program and compare the stack from RAM[256] on
+    fn run_program(
+        name: &str,
+        vm_code: &str,
+        expected: &[i16],
+        configure: impl Fn(&mut VMTranslator),
+    ) {
+        // spin at the end rather than running past the last instruction
+        let program = format!("{}label HALT\ngoto HALT\n", vm_code);
+        let expected = expected
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (256 + i, *value))
+            .collect::<Vec<_>>();
+        run_files(
+            name,
+            &[(name, &program)],
+            &expected,
+            1000 * vm_code.lines().count(),
+            configure,
+        );
+    }
+
+    /// run the `(file stem, vm code)` pairs for `ticks` and compare the
+    /// `(address, value)` pairs of `expected` with the ram afterwards
+    fn run_files(
+        name: &str,
+        files: &[(&str, &str)],
+        expected: &[(usize, i16)],
+        ticks: usize,
+        configure: impl Fn(&mut VMTranslator),
+    ) {
+        let dir = std::env::temp_dir().join("nand2tetris-programs").join(name);
+        std::fs::create_dir_all(&dir).expect("failed to create dir");
+        for (file, vm_code) in files {
+            std::fs::write(dir.join(format!("{}.vm", file)), vm_code)
+                .expect("failed to write file");
+        }
+        let columns = expected
+            .iter()
+            .map(|(address, _)| format!("RAM[{}]", address))
+            .collect::<Vec<String>>();
+        let tst = format!(
+            "load {0}.asm,\noutput-file {0}.out,\ncompare-to {0}.cmp,\n\
+             output-list {1};\nset RAM[0] 256,\nrepeat {2} {{\n  ticktock;\n}}\noutput;\n",
+            name,
+            columns
+                .iter()
+                .map(|c| format!("{}%D2.6.2", c))
+                .collect::<Vec<String>>()
+                .join(" "),
+            ticks
+        );
+        std::fs::write(dir.join(format!("{}.tst", name)), tst).expect("failed to write file");
+        let header = columns
+            .iter()
+            .map(|c| format!("{:^10}", c))
+            .collect::<Vec<String>>();
+        let values = expected
+            .iter()
+            .map(|(_, v)| format!("  {:>6}  ", v))
+            .collect::<Vec<String>>();
+        let cmp = format!("|{}|\n|{}|\n", header.join("|"), values.join("|"));
+        std::fs::write(dir.join(format!("{}.cmp", name)), cmp).expect("failed to write file");
+        run_in(dir, configure);
+    }
+
+    /// vm code pushing any 16 bit value
+    fn push(value: i16) -> String {
+        match value {
+            -32768 => "push constant 32767\nneg\npush constant 1\nsub\n".to_string(),
+            v if v < 0 => format!("push constant {}\nneg\n", -v),
+            v => format!("push constant {}\n", v),
+        }
+    }
+
+    /// `x op y` for values at the edges of the 16 bit range, where `x - y` overflows
+    fn compare_boundaries(name: &str, configure: impl Fn(&mut VMTranslator)) {
+        let cases: &[(i16, &str, i16, i16)] = &[
+            (32767, "gt", -2, -1),
+            (32767, "lt", -2, 0),
+            (-2, "gt", 32767, 0),
+            (-2, "lt", 32767, -1),
+            (-32768, "lt", 1, -1),
+            (-32768, "gt", 1, 0),
+            (0, "gt", -32768, -1),
+            (0, "lt", -32768, 0),
+            (-32768, "lt", 32767, -1),
+            (5, "gt", 3, -1),
+            (3, "lt", 5, -1),
+            (5, "lt", 5, 0),
+            (5, "gt", 5, 0),
+            (-3, "lt", -2, -1),
+            (-32768, "eq", -32768, -1),
+            (32767, "eq", -1, 0),
+        ];
+        let vm_code = cases
+            .iter()
+            .map(|(x, op, y, _)| format!("{}{}{}\n", push(*x), push(*y), op))
+            .collect::<String>();
+        let expected = cases.iter().map(|case| case.3).collect::<Vec<i16>>();
+        run_program(name, &vm_code, &expected, configure);
+    }
+
+    #[test]
+    fn test_compare_boundaries() {
+        compare_boundaries("CompareBoundaries", |_| {})
+    }
+
+    #[test]
+    fn test_compact_compare_boundaries() {
+        compare_boundaries("CompactCompareBoundaries", |t| {
+            t.compact(true);
+        })
+    }
+
+    /// `mul`, `div`, `shl` and `shr` with signs and overflow
+    const EXTENDED_CASES: &[(i16, &str, Option<i16>, i16)] = &[
+        (3, "mul", Some(5), 15),
+        (-3, "mul", Some(5), -15),
+        (-3, "mul", Some(-5), 15),
+        (300, "mul", Some(300), 24464),
+        (181, "mul", Some(181), 32761),
+        (-32768, "mul", Some(-1), -32768),
+        (7, "mul", Some(0), 0),
+        (17, "div", Some(5), 3),
+        (17, "div", Some(-5), -3),
+        (-17, "div", Some(5), -3),
+        (-17, "div", Some(-5), 3),
+        (32767, "div", Some(2), 16383),
+        (-32768, "div", Some(1), -32768),
+        (-32768, "div", Some(-1), -32768),
+        (-32768, "div", Some(-32768), 1),
+        (32767, "div", Some(-32768), 0),
+        (7, "div", Some(0), 0),
+        (-3, "shl", None, -6),
+        (16384, "shl", None, -32768),
+        (5, "shr", None, 2),
+        (-5, "shr", None, -3),
+        (-1, "shr", None, -1),
+        (-32768, "shr", None, -16384),
+        (32767, "shr", None, 16383),
+    ];
+
+    /// run the extended cases in programs small enough for the cpu emulator's output list
+    fn run_extended_cases(name: &str, configure: impl Fn(&mut VMTranslator)) {
+        for (index, cases) in EXTENDED_CASES.chunks(12).enumerate() {
+            let vm_code = cases
+                .iter()
+                .map(|(x, op, y, _)| {
+                    format!("{}{}{}\n", push(*x), y.map_or(String::new(), push), op)
+                })
+                .collect::<String>();
+            let expected = cases.iter().map(|case| case.3).collect::<Vec<i16>>();
+            run_program(
+                &format!("{}{}", name, index),
+                &vm_code,
+                &expected,
+                &configure,
+            );
+        }
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        run_extended_cases("ExtendedCommands", |t| {
+            t.extended_commands(true);
+        });
+    }
+
+    #[test]
+    fn test_compact_extended_commands() {
+        run_extended_cases("CompactExtendedCommands", |t| {
+            t.extended_commands(true).compact(true).optimize(true);
+        });
+    }
+
+    #[test]
+    fn test_extended_isa() {
+        let program = [("Main", "push constant 3\nshl\nshr\nmul\n")];
+        assert_eq!(
+            VMTranslator::new().translate(&program),
+            Err(
+                "Main.vm line 2: `shl` is an extended command, see `extended_commands`".to_string()
+            )
+        );
+        let asm = VMTranslator::new()
+            .extended_commands(true)
+            .extended_isa(true)
+            .translate(&program)
+            .unwrap();
+        assert!(asm.contains("M=M<<\n") && asm.contains("M=M>>\n"));
+        assert!(asm.contains("($$MUL)") && !asm.contains("($$SHR)"));
+        let hack = crate::Assembler::new().process(asm);
+        assert!(hack.contains("1011100000001000\n"));
+        assert!(hack.contains("1011000000001000\n"));
+    }
+
+    #[test]
+    fn test_fast_compare_stack_test() {
+        translate_variant_and_run("07/StackArithmetic/StackTest", "fast-compare", |t| {
+            t.fast_compare(true);
+        })
+    }
+
+    #[test]
+    fn test_fast_compare_is_smaller() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/07/StackArithmetic/StackTest");
+        let count = |fast: bool| {
+            let mut translator = VMTranslator::load(vm_path.clone());
+            translator.fast_compare(fast).process().unwrap();
+            super::instruction_count(&translator.output)
+        };
+        assert!(count(true) < count(false));
+    }
+
+    #[test]
+    fn test_translate_in_memory() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/FibonacciElement");
+        let sources = crate::vm::load_sources(&vm_path).unwrap();
+        let asm = VMTranslator::new()
+            .translate(&crate::vm::as_str_pairs(&sources))
+            .unwrap();
+        let mut translator = VMTranslator::load(vm_path);
+        translator.process().unwrap();
+        assert_eq!(asm, translator.output.join("\n") + "\n");
+
+        // every call translates a program of its own
+        let mut translator = VMTranslator::new();
+        let program = [("Main", "push constant 1\npush constant 2\neq")];
+        let first = translator.translate(&program).unwrap();
+        assert_eq!(translator.translate(&program).unwrap(), first);
+    }
+
+    #[test]
+    fn test_threads_do_not_change_output() {
+        let os_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS");
+        let sources = crate::vm::load_sources(&os_path).unwrap();
+        let sources = crate::vm::as_str_pairs(&sources);
+        let translate = |threads: usize| {
+            let mut translator = VMTranslator::new();
+            let asm = translator
+                .threads(threads)
+                .compact(true)
+                .optimize(true)
+                .translate(&sources)
+                .unwrap();
+            (
+                asm,
+                translator.source_map().to_json(),
+                translator.saved_instructions(),
+            )
+        };
+        let sequential = translate(1);
+        assert_eq!(translate(3), sequential);
+        assert_eq!(translate(0), sequential);
+        assert_eq!(translate(64), sequential);
+    }
+
+    #[test]
+    fn test_profile_fibonacci_element() {
+        let name = "08/FunctionCalls/FibonacciElement";
+        translate_variant_and_run(name, "profile", |t| {
+            t.profile(true);
+        });
+        let dir = std::env::temp_dir().join("nand2tetris-profile").join(name);
+        let mut translator = VMTranslator::load(dir.clone());
+        translator.profile(true).process().unwrap();
+        let counters = translator.profile_counters();
+        let columns = counters
+            .iter()
+            .flat_map(|c| [c.address, c.address + 1])
+            .map(|address| format!("RAM[{}]%D2.8.2", address))
+            .collect::<Vec<String>>();
+        let tst = format!(
+            "load FibonacciElement.asm,\noutput-file Profile.out,\noutput-list {};\n\
+             repeat 6000 {{\n  ticktock;\n}}\noutput;\n",
+            columns.join(" ")
+        );
+        std::fs::write(dir.join("Profile.tst"), tst).expect("failed to write file");
+        run_emulator(&dir.join("Profile.tst"));
+
+        let dump = std::fs::read_to_string(dir.join("Profile.out")).expect("cannot read file");
+        let report =
+            crate::profile::report(counters, &crate::profile::parse_ram_dump(&dump).unwrap());
+        // fibonacci(4) enters fibonacci 9 times, each call site of the recursion runs 4 times
+        assert_eq!(
+            report.functions,
+            vec![
+                ("Main.fibonacci".to_string(), 9),
+                ("Sys.init".to_string(), 1)
+            ]
+        );
+        assert_eq!(
+            report
+                .calls
+                .iter()
+                .map(|(counter, count)| (counter.line, *count))
+                .collect::<Vec<_>>(),
+            vec![(24, 4), (28, 4), (13, 1)]
+        );
+    }
+
+    #[test]
+    fn test_profile_with_os_heap() {
+        let os_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS");
+        let vm_path = std::env::temp_dir().join("nand2tetris-profile/Allocate");
+        std::fs::create_dir_all(&vm_path).expect("failed to create dir");
+        // fill an array of 10000 words and leave its address in temp 1
+        std::fs::write(
+            vm_path.join("Main.vm"),
+            "function Main.main 2\n\
+             push constant 10000\n\
+             call Array.new 1\n\
+             pop local 0\n\
+             push local 0\n\
+             pop temp 1\n\
+             label FILL\n\
+             push local 1\n\
+             push constant 10000\n\
+             lt\n\
+             not\n\
+             if-goto HALT\n\
+             push local 0\n\
+             push local 1\n\
+             add\n\
+             pop pointer 1\n\
+             push constant 1\n\
+             neg\n\
+             pop that 0\n\
+             push local 1\n\
+             push constant 1\n\
+             add\n\
+             pop local 1\n\
+             goto FILL\n\
+             label HALT\n\
+             goto HALT\n",
+        )
+        .expect("failed to write file");
+
+        let mut translator = VMTranslator::load(vm_path);
+        translator
+            .with_os(os_path)
+            .tree_shake(true)
+            .profile(true)
+            .process()
+            .unwrap();
+        let hack = crate::Assembler::new().process(translator.output.join("\n"));
+        let mut cpu = crate::HackCpu::new(&hack).unwrap();
+        assert!(cpu.run(10_000_000).unwrap());
+
+        // Memory.alloc splits the array off the bottom of the free block, the rest of
+        // the heap behind the array ends right below the counters
+        let counters = translator.profile_counters();
+        let rest = cpu.peek(6) as usize + 10000;
+        assert_eq!(rest + 2 + cpu.peek(rest) as usize, counters[0].address);
+        let ram = cpu.ram().iter().copied().enumerate().collect();
+        let report = crate::profile::report(counters, &ram);
+        let entries = |name: &str| {
+            report
+                .functions
+                .iter()
+                .find(|(function, _)| function == name)
+                .map_or(0, |(_, count)| *count)
+        };
+        assert_eq!(entries("Main.main"), 1);
+        assert_eq!(entries("Memory.init"), 1);
+        assert_eq!(entries("Sys.error"), 0);
+        assert!(entries("Memory.alloc") > 1);
+    }
+
+    #[test]
+    fn test_stack_check_overflow() {
+        use crate::translator::{STACK_ERROR, STACK_OVERFLOW};
+        let sys = "function Sys.init 0\ncall Main.recurse 0\nreturn\n";
+        let main = "function Main.recurse 0\npush constant 1\ncall Main.recurse 1\nreturn\n";
+        // every level pushes 1 and a frame of 5, caught at the entry reaching 2048
+        run_files(
+            "StackCheckOverflow",
+            &[("Sys", sys), ("Main", main)],
+            &[(0, 2048), (STACK_ERROR, STACK_OVERFLOW)],
+            50000,
+            |t| {
+                t.stack_check(true);
+            },
+        );
+    }
+
+    #[test]
+    fn test_stack_check_routine_scratch() {
+        use crate::translator::{STACK_ERROR, STACK_OVERFLOW};
+        // SP = 2045 through THAT = 0, the operands end right below RAM[2047] but
+        // the routines would write past it
+        let top = "push constant 0\npop pointer 1\npush constant 2045\npop that 0\n";
+        for (name, operation) in &[
+            ("DivScratch", "push constant 7\npush constant 2\ndiv\n"),
+            ("ShrScratch", "push constant 7\npush constant 2\nshr\n"),
+        ] {
+            let main = format!("{}{}label HALT\ngoto HALT\n", top, operation);
+            run_files(
+                name,
+                &[(name, &main)],
+                &[(0, 2047), (2046, 2), (STACK_ERROR, STACK_OVERFLOW), (2048, 0)],
+                1000,
+                |t| {
+                    t.extended_commands(true).stack_check(true);
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn test_stack_check_underflow() {
+        use crate::translator::{STACK_ERROR, STACK_UNDERFLOW};
+        let main = "pop temp 0\npop temp 0\npush constant 7\nlabel HALT\ngoto HALT\n";
+        run_files(
+            "StackCheckUnderflow",
+            &[("StackCheckUnderflow", main)],
+            &[(0, 255), (STACK_ERROR, STACK_UNDERFLOW)],
+            1000,
+            |t| {
+                t.compact(true).stack_check(true);
+            },
+        );
+    }
+
+    #[test]
+    fn test_stack_checked_projects() {
+        for name in &["08/FunctionCalls/FibonacciElement", "07/StackArithmetic/StackTest"] {
+            translate_variant_and_run(name, "stack-check", |t| {
+                t.stack_check(true);
+            });
+        }
+        translate_variant_and_run(
+            "08/FunctionCalls/NestedCall",
+            "optimized-stack-check",
+            |t| {
+                t.compact(true).optimize(true).stack_check(true);
+            },
+        );
+    }
+
+    #[test]
+    fn test_translate_errors() {
+        let mut translator = VMTranslator::new();
+        assert_eq!(
+            translator.translate(&[("Main", "push constant 1\npush nowhere 2")]),
+            Err("Main.vm line 2: unknown segment nowhere".to_string())
+        );
+        assert!(translator.process().is_err());
+        assert!(translator.write().is_err());
+        let missing = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("missing");
+        assert!(VMTranslator::load(missing).process().is_err());
+        // hack cannot address these, in any mode and on any thread
+        let cases = [
+            ("pop constant 0", "cannot write to constant in `pop constant 0`"),
+            ("push pointer 2", "pointer 2 is out of range in `push pointer 2`"),
+            ("pop temp 8", "temp 8 is out of range in `pop temp 8`"),
+            ("push constant 40000", "constant 40000 is out of range in `push constant 40000`"),
+        ];
+        for (line, error) in &cases {
+            let source = format!("push constant 1\n{}", line);
+            for configure in [
+                |_: &mut VMTranslator| {},
+                |t: &mut VMTranslator| {
+                    t.compact(true).optimize(true);
+                },
+                |t: &mut VMTranslator| {
+                    t.cache_top(true).threads(2);
+                },
+            ] {
+                let mut translator = VMTranslator::new();
+                configure(&mut translator);
+                assert_eq!(
+                    translator.translate(&[("Sys", "function Sys.init 0"), ("Main", &source)]),
+                    Err(format!("Main.vm line 2: {}", error))
+                );
+            }
+        }
+        // commands the parser never produces are errors as well
+        let mut translator = VMTranslator::new();
+        for line in &["pop pointer 2", "push nowhere 1", "call Main.f x", "jump"] {
+            assert!(translator.translate_line(line).is_err(), "{}", line);
         }
     }
 
+    fn translate_compact_and_run(name: &str) {
+        translate_variant_and_run(name, "compact", |t| {
+            t.compact(true);
+        })
+    }
+
     #[test]
     fn test_simple_add() {
         translate_and_run("07/StackArithmetic/SimpleAdd")
@@ -455,4 +2261,389 @@ mod tests {
     fn test_statics_test() {
         translate_and_run("08/FunctionCalls/StaticsTest")
     }
+
+    #[test]
+    fn test_compact_stack_test() {
+        translate_compact_and_run("07/StackArithmetic/StackTest")
+    }
+
+    #[test]
+    fn test_compact_simple_function() {
+        translate_compact_and_run("08/FunctionCalls/SimpleFunction")
+    }
+
+    #[test]
+    fn test_compact_nested_call() {
+        translate_compact_and_run("08/FunctionCalls/NestedCall")
+    }
+
+    #[test]
+    fn test_compact_fibonacci_element() {
+        translate_compact_and_run("08/FunctionCalls/FibonacciElement")
+    }
+
+    #[test]
+    fn test_compact_statics_test() {
+        translate_compact_and_run("08/FunctionCalls/StaticsTest")
+    }
+
+    fn translate_optimized_and_run(name: &str) {
+        translate_variant_and_run(name, "optimized", |t| {
+            t.optimize(true);
+        })
+    }
+
+    #[test]
+    fn test_optimized_basic_test() {
+        translate_optimized_and_run("07/MemoryAccess/BasicTest")
+    }
+
+    #[test]
+    fn test_optimized_pointer_test() {
+        translate_optimized_and_run("07/MemoryAccess/PointerTest")
+    }
+
+    #[test]
+    fn test_optimized_static_test() {
+        translate_optimized_and_run("07/MemoryAccess/StaticTest")
+    }
+
+    #[test]
+    fn test_optimized_basic_loop() {
+        translate_optimized_and_run("08/ProgramFlow/BasicLoop")
+    }
+
+    #[test]
+    fn test_optimized_fibonacci_series() {
+        translate_optimized_and_run("08/ProgramFlow/FibonacciSeries")
+    }
+
+    #[test]
+    fn test_optimized_fibonacci_element() {
+        translate_optimized_and_run("08/FunctionCalls/FibonacciElement")
+    }
+
+    #[test]
+    fn test_optimized_statics_test() {
+        translate_optimized_and_run("08/FunctionCalls/StaticsTest")
+    }
+
+    #[test]
+    fn test_optimized_integer_condition() {
+        // `while (x & 4) { let x = x - 1; let n = n + 1; }` as the jack compiler writes it,
+        // `not` turns 4 into -5 which leaves the loop right away
+        let vm_code = "push constant 7\n\
+                       pop static 0\n\
+                       label WHILE_EXP0\n\
+                       push static 0\n\
+                       push constant 4\n\
+                       and\n\
+                       not\n\
+                       if-goto WHILE_END0\n\
+                       push static 0\n\
+                       push constant 1\n\
+                       sub\n\
+                       pop static 0\n\
+                       push static 1\n\
+                       push constant 1\n\
+                       add\n\
+                       pop static 1\n\
+                       goto WHILE_EXP0\n\
+                       label WHILE_END0\n\
+                       push static 0\n\
+                       push static 1\n";
+        run_program("IntegerCondition", vm_code, &[7, 0], |_| {});
+        run_program("OptimizedIntegerCondition", vm_code, &[7, 0], |t| {
+            t.optimize(true);
+        });
+        run_program("CachedIntegerCondition", vm_code, &[7, 0], |t| {
+            t.optimize(true).cache_top(true);
+        });
+    }
+
+    #[test]
+    fn test_optimized_saves_instructions() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/07/MemoryAccess/BasicTest");
+        let mut translator = VMTranslator::load(vm_path);
+        translator.optimize(true).process().unwrap();
+        assert!(translator.saved_instructions() > 0);
+    }
+
+    /// every project of 07 and 08 with the top of the stack cached in D
+    const PROJECTS: &[&str] = &[
+        "07/StackArithmetic/SimpleAdd",
+        "07/StackArithmetic/StackTest",
+        "07/MemoryAccess/BasicTest",
+        "07/MemoryAccess/PointerTest",
+        "07/MemoryAccess/StaticTest",
+        "08/ProgramFlow/BasicLoop",
+        "08/ProgramFlow/FibonacciSeries",
+        "08/FunctionCalls/SimpleFunction",
+        "08/FunctionCalls/NestedCall",
+        "08/FunctionCalls/FibonacciElement",
+        "08/FunctionCalls/StaticsTest",
+    ];
+
+    #[test]
+    fn test_cached_top_projects() {
+        for name in PROJECTS {
+            translate_variant_and_run(name, "cache-top", |t| {
+                t.cache_top(true);
+            });
+        }
+    }
+
+    #[test]
+    fn test_cached_top_with_other_modes() {
+        for name in PROJECTS {
+            translate_variant_and_run(name, "cache-top-optimized", |t| {
+                t.cache_top(true).optimize(true).fast_compare(true);
+            });
+            translate_variant_and_run(name, "cache-top-compact", |t| {
+                t.cache_top(true).compact(true).stack_check(true);
+            });
+        }
+        compare_boundaries("CachedCompareBoundaries", |t| {
+            t.cache_top(true);
+        });
+        run_extended_cases("CachedExtended", |t| {
+            t.cache_top(true).extended_commands(true);
+        });
+    }
+
+    #[test]
+    fn test_cached_top_saves_instructions() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS");
+        let mut baseline = VMTranslator::load(vm_path.clone());
+        baseline.process().unwrap();
+        let mut cached = VMTranslator::load(vm_path.clone());
+        cached.cache_top(true).process().unwrap();
+        let saved = cached.cached_instructions();
+        assert!(saved > 0);
+        assert_eq!(cached.saved_instructions(), 0);
+        assert_eq!(
+            instruction_count(&baseline.output) - instruction_count(&cached.output),
+            saved
+        );
+
+        // with the peephole optimizer each counts only its own savings
+        let mut optimized = VMTranslator::load(vm_path.clone());
+        optimized.optimize(true).process().unwrap();
+        let mut both = VMTranslator::load(vm_path);
+        both.optimize(true).cache_top(true).process().unwrap();
+        assert_eq!(both.saved_instructions(), optimized.saved_instructions());
+        assert_eq!(
+            instruction_count(&optimized.output) - instruction_count(&both.output),
+            both.cached_instructions()
+        );
+    }
+
+    #[test]
+    fn test_cached_top_saves_cycles() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/FibonacciElement");
+        let run = |cache_top: bool| {
+            let mut translator = VMTranslator::load(vm_path.clone());
+            translator.cache_top(cache_top).process().unwrap();
+            let hack = crate::Assembler::new().process(translator.output.join("\n"));
+            let mut cpu = crate::HackCpu::new(&hack).unwrap();
+            assert!(cpu.run(100_000).unwrap());
+            assert_eq!((cpu.peek(0), cpu.peek(261)), (262, 3));
+            cpu.cycles()
+        };
+        assert!(run(true) < run(false));
+    }
+
+    #[test]
+    fn test_annotated_fibonacci_element() {
+        translate_variant_and_run("08/FunctionCalls/FibonacciElement", "annotated", |t| {
+            t.annotate(true);
+        })
+    }
+
+    #[test]
+    fn test_annotated_output() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/SimpleFunction");
+        let mut translator = VMTranslator::load(vm_path);
+        translator.annotate(true).process().unwrap();
+        let asm = translator.output.join("\n");
+        assert!(asm.contains("// function SimpleFunction.test\n"));
+        assert!(asm.contains("// SimpleFunction.vm:7: function SimpleFunction.test 2\n"));
+        assert!(asm.contains("// SimpleFunction.vm:8: push local 0\n"));
+    }
+
+    #[test]
+    fn test_source_map_matches_assembler() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/FibonacciElement");
+        let mut translator = VMTranslator::load(vm_path);
+        translator.compact(true).optimize(true).process().unwrap();
+        let mut assembler = crate::Assembler::new();
+        let hack = assembler.process(translator.output.join("\n"));
+        let map = translator.source_map();
+        assert_eq!(hack.lines().count(), map.len());
+        assert_eq!(map.lookup(0), None);
+
+        for function in &["Main.fibonacci", "Sys.init"] {
+            let label = &assembler.label_map[*function];
+            let address = usize::from_str_radix(label, 2).unwrap();
+            assert_eq!(map.function_address(function), Some(address));
+            let location = map.lookup(address).unwrap();
+            assert_eq!(location.function, Some(*function));
+            assert_eq!(
+                location.file,
+                format!("{}.vm", &function[..function.find('.').unwrap()])
+            );
+        }
+    }
+
+    #[test]
+    fn test_compact_is_smaller() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/FibonacciElement");
+        let count = |compact: bool| {
+            let mut translator = VMTranslator::load(vm_path.clone());
+            translator.compact(compact).process().unwrap();
+            crate::Assembler::new()
+                .process(translator.output.join("\n"))
+                .lines()
+                .count()
+        };
+        assert!(count(true) < count(false));
+    }
+
+    #[test]
+    fn test_tree_shaken_fibonacci_element() {
+        translate_variant_and_run("08/FunctionCalls/FibonacciElement", "tree-shake", |t| {
+            t.tree_shake(true);
+        })
+    }
+
+    #[test]
+    fn test_tree_shaking_os() {
+        let os_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS");
+        let vm_path = std::env::temp_dir().join("nand2tetris-tree-shake/Multiply");
+        std::fs::create_dir_all(&vm_path).expect("failed to create dir");
+        for entry in std::fs::read_dir(&os_path).expect("failed to read dir") {
+            let path = entry.unwrap().path();
+            std::fs::copy(&path, vm_path.join(path.file_name().unwrap()))
+                .expect("failed to copy file");
+        }
+        std::fs::write(
+            vm_path.join("Main.vm"),
+            "function Main.main 0\n\
+             push constant 6\n\
+             push constant 7\n\
+             call Math.multiply 2\n\
+             return\n\
+             function Main.unused 0\n\
+             call Screen.drawCircle 3\n\
+             return\n",
+        )
+        .expect("failed to write file");
+
+        let mut full = VMTranslator::load(vm_path.clone());
+        full.process().unwrap();
+        let mut shaken = VMTranslator::load(vm_path);
+        shaken.tree_shake(true).process().unwrap();
+        let removed = shaken.removed_functions();
+        assert!(removed.contains(&"Main.unused".to_string()));
+        assert!(removed.contains(&"Screen.drawCircle".to_string()));
+        assert!(!removed.contains(&"Math.multiply".to_string()));
+        assert!(full
+            .output
+            .iter()
+            .any(|code| code.contains("(Screen.drawCircle)")));
+        assert!(!shaken
+            .output
+            .iter()
+            .any(|code| code.contains("(Screen.drawCircle)")));
+        assert!(shaken
+            .output
+            .iter()
+            .any(|code| code.contains("(Math.multiply)")));
+        assert!(super::instruction_count(&shaken.output) < super::instruction_count(&full.output));
+    }
+
+    #[test]
+    fn test_with_os() {
+        let os_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS");
+        let vm_path = std::env::temp_dir().join("nand2tetris-with-os/Override");
+        std::fs::create_dir_all(&vm_path).expect("failed to create dir");
+        std::fs::write(
+            vm_path.join("Main.vm"),
+            "function Main.main 0\npush constant 6\npush constant 7\ncall Math.multiply 2\nreturn\n",
+        )
+        .expect("failed to write file");
+        std::fs::write(
+            vm_path.join("Math.vm"),
+            "function Math.init 0\npush constant 0\nreturn\n\
+             function Math.multiply 0\npush constant 4242\nreturn\n",
+        )
+        .expect("failed to write file");
+
+        let mut translator = VMTranslator::load(vm_path);
+        translator.with_os(os_path).process().unwrap();
+        let asm = translator.output.join("\n");
+        assert!(asm.contains("(Memory.alloc)"));
+        assert!(asm.contains("(Sys.init)"));
+        assert_eq!(asm.matches("(Math.multiply)").count(), 1);
+        assert!(asm.contains("@4242"));
+        assert!(!asm.contains("(Math.divide)"));
+    }
+
+    #[test]
+    fn test_with_os_runs() {
+        // the os functions reuse label names like WHILE_EXP0 and IF_FALSE0
+        let os_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../tools/OS");
+        let vm_path = std::env::temp_dir().join("nand2tetris-with-os/Multiply");
+        std::fs::create_dir_all(&vm_path).expect("failed to create dir");
+        std::fs::write(
+            vm_path.join("Main.vm"),
+            "function Main.main 0\n\
+             push constant 6\n\
+             push constant 7\n\
+             call Math.multiply 2\n\
+             pop temp 0\n\
+             label WHILE_EXP0\n\
+             goto WHILE_EXP0\n",
+        )
+        .expect("failed to write file");
+
+        let mut translator = VMTranslator::load(vm_path);
+        translator.with_os(os_path).tree_shake(true).process().unwrap();
+        let hack = crate::Assembler::new().process(translator.output.join("\n"));
+        let mut cpu = crate::HackCpu::new(&hack).unwrap();
+        assert!(cpu.run(10_000_000).unwrap());
+        assert_eq!(cpu.peek(5), 42);
+    }
+
+    #[test]
+    fn test_inlined_nested_call() {
+        translate_variant_and_run("08/FunctionCalls/NestedCall", "inline", |t| {
+            t.inline(8);
+        })
+    }
+
+    #[test]
+    fn test_inlined_and_optimized_nested_call() {
+        translate_variant_and_run("08/FunctionCalls/NestedCall", "inline-optimized", |t| {
+            t.inline(8).optimize(true).tree_shake(true);
+        })
+    }
+
+    #[test]
+    fn test_inline_replaces_calls() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/NestedCall");
+        let mut translator = VMTranslator::load(vm_path);
+        translator.inline(8).tree_shake(true).process().unwrap();
+        assert_eq!(translator.inlined_calls(), 1);
+        assert_eq!(translator.removed_functions(), ["Sys.add12"]);
+        let asm = translator.output.join("\n");
+        assert!(!asm.contains("@Sys.add12\n"));
+        assert!(asm.contains("@Sys.0\n"));
+    }
 }
@@ -0,0 +1,382 @@
+use crate::vm::{self, Command, Segment};
+use std::collections::{HashMap, HashSet};
+
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+typedef int16_t word;
+
+word RAM[32768];
+#define M(address) RAM[(uint16_t)(address) & 0x7fff]
+#define SP RAM[0]
+#define LCL RAM[1]
+#define ARG RAM[2]
+#define THIS RAM[3]
+#define THAT RAM[4]
+#define TOP M(SP - 1)
+#define SCREEN 16384
+#define KBD 24576
+
+/* called after every write into the screen memory map, offset is relative to SCREEN */
+void vm_screen(int offset, word value);
+/* called on every read of KBD, returns the key currently held down */
+word vm_keyboard(void);
+
+#ifndef VM_CUSTOM_HOOKS
+void vm_screen(int offset, word value) { (void)offset; (void)value; }
+word vm_keyboard(void) { return 0; }
+#endif
+
+void vm_dump(void) {
+    for (int i = 0; i < SCREEN; i++) {
+        if (RAM[i] != 0) printf("RAM[%d] = %d\n", i, RAM[i]);
+    }
+}
+
+/* define VM_MAX_JUMPS to stop (and dump ram) after that many jumps */
+#ifdef VM_MAX_JUMPS
+static long vm_jumps;
+#define VM_TICK() do { if (++vm_jumps > VM_MAX_JUMPS) { vm_dump(); exit(0); } } while (0)
+#else
+#define VM_TICK() ((void)0)
+#endif
+
+static inline word peek(int address) {
+    address &= 0x7fff;
+    if (address == KBD) RAM[KBD] = vm_keyboard();
+    return RAM[address];
+}
+
+static inline void poke(int address, word value) {
+    address &= 0x7fff;
+    RAM[address] = value;
+    if (address >= SCREEN && address < KBD) vm_screen(address - SCREEN, value);
+}
+
+static inline void push(word value) { M(SP) = value; SP++; }
+static inline word pop(void) { SP--; return M(SP); }
+
+static inline void vm_call(void (*function)(void), int n_args) {
+    push(0); /* the return address lives on the c stack */
+    push(LCL);
+    push(ARG);
+    push(THIS);
+    push(THAT);
+    ARG = (word)(SP - n_args - 5);
+    LCL = SP;
+    function();
+}
+
+static inline void vm_return(void) {
+    word frame = LCL;
+    M(ARG) = pop();
+    SP = (word)(ARG + 1);
+    THAT = M(frame - 1);
+    THIS = M(frame - 2);
+    ARG = M(frame - 3);
+    LCL = M(frame - 4);
+}
+"#;
+
+const MAIN: &str = r#"
+#ifndef VM_NO_MAIN
+int main(void) {
+    vm_run();
+    vm_dump();
+    return 0;
+}
+#endif
+"#;
+
+/// translate `(file stem, vm code)` pairs into a single portable c program.
+///
+/// every vm function becomes a c function, labels become `goto`s and the hack
+/// ram, stack and frames are kept in `RAM` exactly like the asm backend does, so
+/// dumps can be compared. writes into the screen and reads of `KBD` go through
+/// the `vm_screen` / `vm_keyboard` hooks, define `VM_CUSTOM_HOOKS` to provide
+/// your own. without `Sys.init` the code outside of functions runs instead.
+pub fn translate(sources: &[(&str, &str)]) -> Result<String, String> {
+    let mut files = vec![];
+    for (name, source) in sources {
+        let commands = vm::parse(source).map_err(|e| format!("{}.vm {}", name, e))?;
+        files.push((*name, commands));
+    }
+    let mut backend = CBackend::default();
+    for (_, commands) in &files {
+        for (_, command) in commands {
+            if let Command::Function(name, _) = command {
+                backend.functions.insert(name.clone());
+            }
+        }
+    }
+
+    backend.emit(PRELUDE);
+    let mut names = backend.functions.iter().cloned().collect::<Vec<String>>();
+    names.sort();
+    for name in &names {
+        backend.emit(&format!("void {}(void);", mangle(name)));
+    }
+    backend.emit("void vm_toplevel(void);");
+
+    let mut toplevel = vec![];
+    for (file, commands) in &files {
+        backend.file = file.to_string();
+        let mut in_function = false;
+        for (line, command) in commands {
+            if let Command::Function(..) = command {
+                if in_function {
+                    backend.emit("}");
+                }
+                in_function = true;
+            }
+            if in_function {
+                backend
+                    .translate(command)
+                    .map_err(|e| format!("{}.vm line {}: {}", file, line, e))?;
+            } else {
+                toplevel.push((file.to_string(), *line, command.clone()));
+            }
+        }
+        if in_function {
+            backend.emit("}");
+        }
+    }
+
+    backend.emit("\nvoid vm_toplevel(void) {");
+    for (file, line, command) in &toplevel {
+        backend.file = file.to_string();
+        backend
+            .translate(command)
+            .map_err(|e| format!("{}.vm line {}: {}", file, line, e))?;
+    }
+    backend.emit("}");
+
+    backend.emit("\nvoid vm_run(void) {\n    SP = 256;");
+    if backend.functions.contains("Sys.init") {
+        backend.emit(&format!("    vm_call({}, 0);", mangle("Sys.init")));
+    } else {
+        backend.emit("    vm_toplevel();");
+    }
+    backend.emit("}");
+    backend.emit(MAIN);
+    Ok(backend.output.join("\n"))
+}
+
+#[derive(Default)]
+struct CBackend {
+    output: Vec<String>,
+    functions: HashSet<String>,
+    statics: HashMap<(String, u16), usize>,
+    file: String,
+}
+
+impl CBackend {
+    fn emit(&mut self, code: &str) {
+        self.output.push(code.to_string())
+    }
+
+    fn translate(&mut self, command: &Command) -> Result<(), String> {
+        let code = match command {
+            Command::Push(Segment::Constant, i) => format!("push({});", i),
+            Command::Push(segment, i) => format!("push({});", self.read(*segment, *i)?),
+            Command::Pop(segment, i) => self.write(*segment, *i, "pop()")?,
+            Command::Move(from, i, to, j) => {
+                let value = match from {
+                    Segment::Constant => i.to_string(),
+                    _ => self.read(*from, *i)?,
+                };
+                self.write(*to, *j, &value)?
+            }
+            Command::Add => binary("TOP + y"),
+            Command::Sub => binary("TOP - y"),
+            Command::And => binary("TOP & y"),
+            Command::Or => binary("TOP | y"),
+            Command::Eq => binary("-(TOP == y)"),
+            Command::Gt => binary("-(TOP > y)"),
+            Command::Lt => binary("-(TOP < y)"),
+            Command::Mul => binary("TOP * y"),
+            Command::Div => binary("y ? TOP / y : 0"),
+            Command::Shl => "TOP = (word)(TOP * 2);".to_string(),
+            Command::Shr => "TOP = (word)(TOP >> 1);".to_string(),
+            Command::Neg => "TOP = (word)-TOP;".to_string(),
+            Command::Not => "TOP = (word)~TOP;".to_string(),
+            Command::Inc => "TOP = (word)(TOP + 1);".to_string(),
+            Command::Label(label) => format!("L_{}:;", mangle(label)),
+            Command::Goto(label) => format!("VM_TICK(); goto L_{};", mangle(label)),
+            Command::IfGoto(label) => format!("VM_TICK(); if (pop()) goto L_{};", mangle(label)),
+            Command::IfNotGoto(label) => {
+                format!("VM_TICK(); if (pop() != -1) goto L_{};", mangle(label))
+            }
+            Command::Function(name, n_locals) => {
+                let mut code = format!("\nvoid {}(void) {{", mangle(name));
+                if *n_locals > 0 {
+                    code += &format!("\n    for (int i = 0; i < {}; i++) push(0);", n_locals);
+                }
+                self.emit(&code);
+                return Ok(());
+            }
+            Command::Call(name, n_args) => {
+                if !self.functions.contains(name) {
+                    return Err(format!("function {} is not defined", name));
+                }
+                format!("vm_call({}, {});", mangle(name), n_args)
+            }
+            Command::Return => "vm_return(); return;".to_string(),
+        };
+        self.emit(&format!("    {}", code));
+        Ok(())
+    }
+
+    fn address(&mut self, segment: Segment, i: u16) -> Result<String, String> {
+        Ok(match segment {
+            Segment::Local => format!("LCL + {}", i),
+            Segment::Argument => format!("ARG + {}", i),
+            Segment::This => format!("THIS + {}", i),
+            Segment::That => format!("THAT + {}", i),
+            Segment::Pointer if i < 2 => (3 + i).to_string(),
+            Segment::Temp if i < 8 => (5 + i).to_string(),
+            Segment::Static => {
+                let next = 16 + self.statics.len();
+                let address = *self.statics.entry((self.file.clone(), i)).or_insert(next);
+                address.to_string()
+            }
+            _ => return Err(format!("{} {} is out of range", segment.as_str(), i)),
+        })
+    }
+
+    /// this/that may point into the memory map, everything else is plain ram.
+    fn read(&mut self, segment: Segment, i: u16) -> Result<String, String> {
+        let address = self.address(segment, i)?;
+        Ok(match segment {
+            Segment::This | Segment::That => format!("peek({})", address),
+            _ => format!("M({})", address),
+        })
+    }
+
+    fn write(&mut self, segment: Segment, i: u16, value: &str) -> Result<String, String> {
+        let address = match segment {
+            Segment::Constant => return Err("cannot pop to constant".to_string()),
+            _ => self.address(segment, i)?,
+        };
+        Ok(match segment {
+            Segment::This | Segment::That => format!("poke({}, {});", address, value),
+            _ => format!("M({}) = {};", address, value),
+        })
+    }
+}
+
+fn binary(expression: &str) -> String {
+    format!("{{ word y = pop(); TOP = (word)({}); }}", expression)
+}
+
+/// vm names into c identifiers, `_` is escaped so the mapping stays unique.
+pub(super) fn mangle(name: &str) -> String {
+    let mut out = String::from("vm_");
+    for c in name.chars() {
+        match c {
+            '_' => out.push_str("__"),
+            '.' => out.push_str("_d"),
+            '$' => out.push_str("_s"),
+            ':' => out.push_str("_c"),
+            c if c.is_ascii_alphanumeric() => out.push(c),
+            c => out.push_str(&format!("_x{:x}", c as u32)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_mangle() {
+        assert_eq!(mangle("Main.main"), "vm_Main_dmain");
+        assert_ne!(mangle("A_d.b"), mangle("A.d_b"));
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        assert!(translate(&[("Main", "function Main.main 0\ncall Foo.bar 0")]).is_err());
+    }
+
+    /// compile the generated c with `cc` and compare its ram dump with the `.cmp` file
+    fn compile_and_run(name: &str) {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/")
+            .join(name);
+        let filename = vm_path.file_name().unwrap().to_string_lossy().to_string();
+        let sources = vm::load_sources(&vm_path).unwrap();
+        let c_code = translate(&vm::as_str_pairs(&sources)).unwrap();
+
+        let out_dir = std::env::temp_dir().join("nand2tetris-c").join(name);
+        std::fs::create_dir_all(&out_dir).expect("failed to create dir");
+        let c_path = out_dir.join(format!("{}.c", filename));
+        let exe_path = out_dir.join(&filename);
+        std::fs::write(&c_path, c_code).expect("failed to write file");
+        let status = std::process::Command::new("cc")
+            .args(["-O1", "-Wall", "-Wno-unused-label", "-Werror"])
+            .args(["-DVM_MAX_JUMPS=100000", "-o"])
+            .arg(&exe_path)
+            .arg(&c_path)
+            .status()
+            .expect("failed to run cc");
+        assert!(status.success());
+        let output = std::process::Command::new(&exe_path)
+            .output()
+            .expect("failed to run compiled program");
+        let dump = String::from_utf8_lossy(&output.stdout);
+        let ram: HashMap<usize, i16> = dump
+            .lines()
+            .map(|line| {
+                let (address, value) = line.split_at(line.find(" = ").unwrap());
+                (
+                    address[4..address.len() - 1].parse().unwrap(),
+                    value[3..].parse().unwrap(),
+                )
+            })
+            .collect();
+
+        let cmp = std::fs::read_to_string(vm_path.join(format!("{}.cmp", filename)))
+            .expect("failed to read compare file");
+        let mut lines = cmp.lines();
+        let header = lines
+            .next()
+            .unwrap()
+            .split('|')
+            .filter(|s| !s.trim().is_empty());
+        let values = lines
+            .next()
+            .unwrap()
+            .split('|')
+            .filter(|s| !s.trim().is_empty());
+        for (column, value) in header.zip(values) {
+            let digits: String = column.chars().filter(|c| c.is_ascii_digit()).collect();
+            let address: usize = digits.parse().unwrap();
+            let expected: i16 = value.trim().parse().unwrap();
+            assert_eq!(
+                ram.get(&address).copied().unwrap_or(0),
+                expected,
+                "RAM[{}]",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn test_fibonacci_element() {
+        compile_and_run("08/FunctionCalls/FibonacciElement")
+    }
+
+    #[test]
+    fn test_statics_test() {
+        compile_and_run("08/FunctionCalls/StaticsTest")
+    }
+
+    #[test]
+    fn test_nested_call() {
+        compile_and_run("08/FunctionCalls/NestedCall")
+    }
+}
@@ -0,0 +1,618 @@
+use super::c::mangle;
+use super::static_symbol;
+use crate::vm::{self, Command, Segment};
+use std::collections::{HashMap, HashSet};
+
+const PRELUDE: &str = r#"// generated from vm code, build with `rustc --edition 2021 -O <NAME>.rs`
+#![allow(non_snake_case, non_upper_case_globals, unreachable_code, unused_mut, unused_variables, dead_code)]
+
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver};
+
+const SP: usize = 0;
+const LCL: usize = 1;
+const ARG: usize = 2;
+const THIS: usize = 3;
+const THAT: usize = 4;
+const SCREEN: usize = 16384;
+const KBD: usize = 24576;
+
+/// braille dot bits of a 2x4 pixel cell, by (x, y)
+const DOTS: [(usize, usize); 8] = [(0, 0), (0, 1), (0, 2), (1, 0), (1, 1), (1, 2), (0, 3), (1, 3)];
+
+/// the hack ram. where the hardware would silently read or write some other
+/// word, like an address outside of the ram or a pop from an empty stack, this
+/// panics instead. arithmetic of the vm commands wraps like the hardware's.
+struct Vm {
+    ram: [i16; 32768],
+    jumps: u64,
+    max_jumps: Option<u64>,
+    /// draw the screen in the terminal while running
+    screen: bool,
+    dirty: bool,
+    keys: Receiver<u8>,
+}
+
+impl Vm {
+    fn address(address: i32) -> usize {
+        match usize::try_from(address) {
+            Ok(address) if address <= KBD => address,
+            _ => panic!("address {} is outside of the ram", address),
+        }
+    }
+
+    /// the address of word `i` of the segment based at `ram[pointer]`
+    fn segment(&self, pointer: usize, i: u16) -> usize {
+        Self::address(self.ram[pointer] as i32 + i as i32)
+    }
+
+    fn peek(&self, address: usize) -> i16 {
+        self.ram[address]
+    }
+
+    fn poke(&mut self, address: usize, value: i16) {
+        self.ram[address] = value;
+        self.dirty |= (SCREEN..KBD).contains(&address);
+    }
+
+    fn push(&mut self, value: i16) {
+        let sp = Self::address(self.ram[SP] as i32);
+        self.poke(sp, value);
+        self.ram[SP] += 1;
+    }
+
+    fn pop(&mut self) -> i16 {
+        self.ram[SP] -= 1;
+        self.ram[Self::address(self.ram[SP] as i32)]
+    }
+
+    fn push_from(&mut self, address: usize) {
+        self.push(self.ram[address]);
+    }
+
+    fn pop_to(&mut self, address: usize) {
+        let value = self.pop();
+        self.poke(address, value);
+    }
+
+    fn unary(&mut self, op: fn(i16) -> i16) {
+        let x = self.pop();
+        self.push(op(x));
+    }
+
+    fn binary(&mut self, op: fn(i16, i16) -> i16) {
+        let y = self.pop();
+        let x = self.pop();
+        self.push(op(x, y));
+    }
+
+    fn enter(&mut self, n_locals: u16) {
+        for _ in 0..n_locals {
+            self.push(0);
+        }
+    }
+
+    /// everything of `call f n` but the return address, which lives on the native stack
+    fn call(&mut self, function: fn(&mut Vm), n_args: i16) {
+        self.push(0);
+        for pointer in LCL..=THAT {
+            self.push(self.ram[pointer]);
+        }
+        self.ram[ARG] = self.ram[SP] - n_args - 5;
+        self.ram[LCL] = self.ram[SP];
+        function(self);
+    }
+
+    fn ret(&mut self) {
+        let frame = self.ram[LCL] as i32;
+        let value = self.pop();
+        self.poke(self.segment(ARG, 0), value);
+        self.ram[SP] = self.ram[ARG] + 1;
+        for (offset, pointer) in (LCL..=THAT).rev().enumerate() {
+            self.ram[pointer] = self.ram[Self::address(frame - 1 - offset as i32)];
+        }
+    }
+
+    /// count a jump, now and then read a key into KBD and redraw the screen
+    fn tick(&mut self) {
+        self.jumps += 1;
+        if self.max_jumps.is_some_and(|max| self.jumps > max) {
+            self.halt();
+        }
+        if self.jumps % 4096 == 0 {
+            // hack key codes for newline and backspace
+            self.ram[KBD] = match self.keys.try_recv() {
+                Ok(b'\n') => 128,
+                Ok(127) => 129,
+                Ok(key) => key as i16,
+                Err(_) => 0,
+            };
+            if self.screen && self.dirty {
+                self.render();
+            }
+        }
+    }
+
+    /// the 512x256 screen as 256x64 braille characters
+    fn render(&mut self) {
+        let mut out = String::from("\x1b[H");
+        for row in 0..64 {
+            for column in 0..256 {
+                let mut dots = 0;
+                for (bit, (dx, dy)) in DOTS.iter().enumerate() {
+                    let (x, y) = (2 * column + dx, 4 * row + dy);
+                    if self.ram[SCREEN + 32 * y + x / 16] >> (x % 16) & 1 != 0 {
+                        dots |= 1 << bit;
+                    }
+                }
+                out.push(char::from_u32(0x2800 + dots).unwrap());
+            }
+            out.push('\n');
+        }
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(out.as_bytes()).and_then(|_| stdout.flush());
+        self.dirty = false;
+    }
+
+    /// draw the screen if asked to, print the non zero ram below it and exit
+    fn halt(&mut self) -> ! {
+        if self.screen {
+            self.render();
+        }
+        let mut stdout = std::io::stdout().lock();
+        for (address, value) in self.ram[..SCREEN].iter().enumerate() {
+            if *value != 0 {
+                let _ = writeln!(stdout, "RAM[{}] = {}", address, value);
+            }
+        }
+        let _ = stdout.flush();
+        std::process::exit(0)
+    }
+}
+"#;
+
+const MAIN: &str = r#"
+fn main() {
+    let mut max_jumps = None;
+    let mut screen = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--screen" => screen = true,
+            "--max-jumps" => max_jumps = args.next().and_then(|n| n.parse().ok()),
+            _ => {
+                eprintln!("usage: [--screen] [--max-jumps <N>]");
+                std::process::exit(2);
+            }
+        }
+    }
+    let (sender, keys) = mpsc::channel();
+    std::thread::spawn(move || {
+        for key in std::io::stdin().bytes().map_while(Result::ok) {
+            if sender.send(key).is_err() {
+                break;
+            }
+        }
+    });
+    if screen {
+        print!("\x1b[2J");
+    }
+    let mut vm = Vm {
+        ram: [0; 32768],
+        jumps: 0,
+        max_jumps,
+        screen,
+        dirty: false,
+        keys,
+    };
+    vm.ram[SP] = 256;
+    run(&mut vm);
+    vm.halt();
+}
+"#;
+
+/// translate `(file stem, vm code)` pairs into a single rust program, to be built
+/// with `rustc`.
+///
+/// every vm function becomes a rust function taking the `Vm`, whose labels split
+/// it into the blocks of a `loop`/`match`. the ram, stack and frames are kept in
+/// `Vm::ram` like the asm backend does and statics use the same `File.i` symbols,
+/// placed from 16 on in order of first use, so ram dumps match the cpu emulator's.
+/// unlike the hardware the program panics on addresses outside of the ram and on
+/// functions running past their end, which makes translator bugs obvious when built
+/// with overflow checks. it prints the non zero ram below the screen when it halts,
+/// after `--max-jumps <N>` jumps or when the code outside of functions ends, and
+/// `--screen` draws the screen in the terminal. without `Sys.init` the code outside
+/// of functions runs instead.
+pub fn translate(sources: &[(&str, &str)]) -> Result<String, String> {
+    let mut files = vec![];
+    for (name, source) in sources {
+        let commands = vm::parse(source).map_err(|e| format!("{}.vm {}", name, e))?;
+        files.push((*name, commands));
+    }
+    let mut backend = RustBackend::default();
+    let mut functions: Vec<Body> = vec![];
+    let mut toplevel = Body {
+        name: None,
+        commands: vec![],
+    };
+    for (file, commands) in &files {
+        let mut in_function = false;
+        for (line, command) in commands {
+            if let Command::Function(name, n_locals) = command {
+                backend.functions.insert(name.clone());
+                functions.push(Body {
+                    name: Some((name.clone(), *n_locals)),
+                    commands: vec![],
+                });
+                in_function = true;
+                continue;
+            }
+            let body = match functions.last_mut() {
+                Some(function) if in_function => function,
+                _ => &mut toplevel,
+            };
+            body.commands
+                .push((file.to_string(), *line, command.clone()));
+        }
+    }
+
+    backend.emit(PRELUDE);
+    for function in &functions {
+        backend.body(function)?;
+    }
+    backend.body(&toplevel)?;
+
+    backend.emit("\nfn run(vm: &mut Vm) {");
+    if backend.functions.contains("Sys.init") {
+        backend.emit(&format!("    vm.call({}, 0);", mangle("Sys.init")));
+    } else {
+        backend.emit("    vm_toplevel(vm);");
+    }
+    backend.emit("}");
+    backend.emit(MAIN);
+    for (address, symbol) in backend.statics.iter().enumerate() {
+        backend.output.push(format!(
+            "const {}: usize = {}; // {}",
+            mangle(symbol),
+            16 + address,
+            symbol
+        ));
+    }
+    Ok(backend.output.join("\n") + "\n")
+}
+
+/// a vm function, or the code outside of functions when `name` is `None`.
+struct Body {
+    /// name and local count
+    name: Option<(String, u16)>,
+    /// file stem, line and command
+    commands: Vec<(String, usize, Command)>,
+}
+
+#[derive(Default)]
+struct RustBackend {
+    output: Vec<String>,
+    functions: HashSet<String>,
+    /// static symbols in order of first use
+    statics: Vec<String>,
+    file: String,
+    /// block index of every label of the current body
+    blocks: HashMap<String, usize>,
+    /// name of the current body, for messages
+    scope: String,
+}
+
+impl RustBackend {
+    fn emit(&mut self, code: &str) {
+        self.output.push(code.to_string())
+    }
+
+    /// one rust function for `body`. labels start the blocks of a `loop`/`match`
+    /// that jumps set and `continue`, straight line code needs neither.
+    fn body(&mut self, body: &Body) -> Result<(), String> {
+        let labels = body
+            .commands
+            .iter()
+            .filter_map(|(_, _, command)| match command {
+                Command::Label(label) => Some(label.clone()),
+                _ => None,
+            });
+        self.blocks = labels.zip(1..).collect();
+        match &body.name {
+            Some((name, n_locals)) => {
+                self.scope = name.clone();
+                self.emit(&format!(
+                    "\n/// {}\nfn {}(vm: &mut Vm) {{",
+                    name,
+                    mangle(name)
+                ));
+                if *n_locals > 0 {
+                    self.emit(&format!("    vm.enter({});", n_locals));
+                }
+            }
+            None => {
+                self.scope = "the code outside of functions".to_string();
+                self.emit("\nfn vm_toplevel(vm: &mut Vm) {");
+            }
+        }
+        let indent = match self.blocks.is_empty() {
+            true => "    ",
+            false => {
+                self.emit(
+                    "    let mut block = 0;\n    loop {\n        match block {\n            0 => {",
+                );
+                "                "
+            }
+        };
+        for (file, line, command) in &body.commands {
+            if let Command::Label(label) = command {
+                let block = self.blocks[label];
+                self.emit(&format!(
+                    "                block = {0};\n            }}\n            {0} => {{ // {1}",
+                    block, label
+                ));
+                continue;
+            }
+            self.file = file.clone();
+            let code = self
+                .translate(command)
+                .map_err(|e| format!("{}.vm line {}: {}", file, line, e))?;
+            for line in code.lines() {
+                self.emit(&format!("{}{}", indent, line));
+            }
+        }
+        let ends = matches!(
+            body.commands.last(),
+            Some((_, _, Command::Return)) | Some((_, _, Command::Goto(_)))
+        );
+        if !ends {
+            match &body.name {
+                Some((name, _)) => self.emit(&format!(
+                    "{}panic!(\"{} runs past its end\");",
+                    indent, name
+                )),
+                None if !self.blocks.is_empty() => self.emit(&format!("{}return;", indent)),
+                None => {}
+            }
+        }
+        if !self.blocks.is_empty() {
+            self.emit("            }\n            _ => unreachable!(),\n        }\n    }");
+        }
+        self.emit("}");
+        Ok(())
+    }
+
+    fn translate(&mut self, command: &Command) -> Result<String, String> {
+        Ok(match command {
+            Command::Push(Segment::Constant, i) => format!("vm.push({});", i),
+            Command::Push(segment, i) => format!("vm.push_from({});", self.address(*segment, *i)?),
+            Command::Pop(segment, i) => format!("vm.pop_to({});", self.address(*segment, *i)?),
+            Command::Move(from, i, to, j) => {
+                let value = match from {
+                    Segment::Constant => i.to_string(),
+                    _ => format!("vm.peek({})", self.address(*from, *i)?),
+                };
+                format!("vm.poke({}, {});", self.address(*to, *j)?, value)
+            }
+            Command::Add => binary("x.wrapping_add(y)"),
+            Command::Sub => binary("x.wrapping_sub(y)"),
+            Command::And => binary("x & y"),
+            Command::Or => binary("x | y"),
+            Command::Eq => binary("-((x == y) as i16)"),
+            Command::Gt => binary("-((x > y) as i16)"),
+            Command::Lt => binary("-((x < y) as i16)"),
+            Command::Mul => binary("x.wrapping_mul(y)"),
+            Command::Div => binary("if y == 0 { 0 } else { x.wrapping_div(y) }"),
+            Command::Shl => unary("x << 1"),
+            Command::Shr => unary("x >> 1"),
+            Command::Neg => unary("x.wrapping_neg()"),
+            Command::Not => unary("!x"),
+            Command::Inc => unary("x.wrapping_add(1)"),
+            Command::Goto(label) => {
+                format!("vm.tick();\nblock = {};\ncontinue;", self.block(label)?)
+            }
+            Command::IfGoto(label) => format!(
+                "vm.tick();\nif vm.pop() != 0 {{\n    block = {};\n    continue;\n}}",
+                self.block(label)?
+            ),
+            Command::IfNotGoto(label) => format!(
+                "vm.tick();\nif vm.pop() != -1 {{\n    block = {};\n    continue;\n}}",
+                self.block(label)?
+            ),
+            Command::Call(name, n_args) => {
+                if !self.functions.contains(name) {
+                    return Err(format!("function {} is not defined", name));
+                }
+                format!("vm.call({}, {});", mangle(name), n_args)
+            }
+            Command::Return => "vm.ret();\nreturn;".to_string(),
+            Command::Label(_) | Command::Function(..) => unreachable!("handled by `body`"),
+        })
+    }
+
+    fn block(&self, label: &str) -> Result<usize, String> {
+        self.blocks
+            .get(label)
+            .copied()
+            .ok_or(format!("label {} is not defined in {}", label, self.scope))
+    }
+
+    /// the `usize` expression for the address of segment[i]
+    fn address(&mut self, segment: Segment, i: u16) -> Result<String, String> {
+        Ok(match segment {
+            Segment::Local => format!("vm.segment(LCL, {})", i),
+            Segment::Argument => format!("vm.segment(ARG, {})", i),
+            Segment::This => format!("vm.segment(THIS, {})", i),
+            Segment::That => format!("vm.segment(THAT, {})", i),
+            Segment::Pointer if i < 2 => ["THIS", "THAT"][i as usize].to_string(),
+            Segment::Temp if i < 8 => (5 + i).to_string(),
+            Segment::Static => {
+                let symbol = static_symbol(&self.file, i);
+                if !self.statics.contains(&symbol) {
+                    self.statics.push(symbol.clone());
+                }
+                mangle(&symbol)
+            }
+            _ => return Err(format!("{} {} is out of range", segment.as_str(), i)),
+        })
+    }
+}
+
+fn unary(expression: &str) -> String {
+    format!("vm.unary(|x| {});", expression)
+}
+
+fn binary(expression: &str) -> String {
+    format!("vm.binary(|x, y| {});", expression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::process::Output;
+
+    #[test]
+    fn test_errors() {
+        assert!(translate(&[("Main", "function Main.main 0\ncall Foo.bar 0")]).is_err());
+        assert!(translate(&[("Main", "function Main.main 0\npush temp 8")]).is_err());
+        assert_eq!(
+            translate(&[(
+                "Main",
+                "function Main.main 0\ngoto END\nfunction Main.f 0\nlabel END"
+            )]),
+            Err("Main.vm line 2: label END is not defined in Main.main".to_string())
+        );
+    }
+
+    #[test]
+    fn test_statics_are_shared_symbols() {
+        let code =
+            translate(&[("A", "push static 1\npop static 0"), ("B", "push static 0")]).unwrap();
+        assert!(code.contains("vm.push_from(vm_A_d1);"));
+        assert!(code.contains(
+            "const vm_A_d1: usize = 16; // A.1\n\
+             const vm_A_d0: usize = 17; // A.0\n\
+             const vm_B_d0: usize = 18; // B.0\n"
+        ));
+    }
+
+    /// build with `rustc` and overflow checks, then run with a jump budget
+    fn compile_and_run(name: &str, code: &str) -> Output {
+        let out_dir = std::env::temp_dir().join("nand2tetris-rust").join(name);
+        std::fs::create_dir_all(&out_dir).expect("failed to create dir");
+        let rs_path = out_dir.join("out.rs");
+        let exe_path = out_dir.join("out");
+        std::fs::write(&rs_path, code).expect("failed to write file");
+        let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+        let status = std::process::Command::new(rustc)
+            .args([
+                "--edition",
+                "2021",
+                "-C",
+                "overflow-checks=on",
+                "-D",
+                "warnings",
+            ])
+            .arg("-o")
+            .arg(&exe_path)
+            .arg(&rs_path)
+            .status()
+            .expect("failed to run rustc");
+        assert!(status.success());
+        std::process::Command::new(&exe_path)
+            .args(["--max-jumps", "100000"])
+            .stdin(std::process::Stdio::null())
+            .output()
+            .expect("failed to run compiled program")
+    }
+
+    /// the `RAM[i] = v` lines printed when the program halts
+    fn ram_dump(output: &Output) -> HashMap<usize, i16> {
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| {
+                let (address, value) = line.split_at(line.find(" = ").unwrap());
+                (
+                    address[4..address.len() - 1].parse().unwrap(),
+                    value[3..].parse().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// compare the first row of the project's `.cmp` file with the native run
+    fn run_project(name: &str) {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/")
+            .join(name);
+        let filename = vm_path.file_name().unwrap().to_string_lossy().to_string();
+        let sources = vm::load_sources(&vm_path).unwrap();
+        let code = translate(&vm::as_str_pairs(&sources)).unwrap();
+        let ram = ram_dump(&compile_and_run(name, &code));
+
+        let cmp = std::fs::read_to_string(vm_path.join(format!("{}.cmp", filename)))
+            .expect("failed to read compare file");
+        let mut rows = cmp.lines().map(|line| {
+            line.split('|')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| s.trim().to_string())
+                .collect::<Vec<String>>()
+        });
+        let (header, values) = (rows.next().unwrap(), rows.next().unwrap());
+        for (column, value) in header.iter().zip(values) {
+            let address: usize = column[4..column.len() - 1].parse().unwrap();
+            let expected: i16 = value.parse().unwrap();
+            assert_eq!(
+                ram.get(&address).copied().unwrap_or(0),
+                expected,
+                "RAM[{}]",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn test_fibonacci_element() {
+        run_project("08/FunctionCalls/FibonacciElement")
+    }
+
+    #[test]
+    fn test_statics_test() {
+        run_project("08/FunctionCalls/StaticsTest")
+    }
+
+    #[test]
+    fn test_nested_call() {
+        run_project("08/FunctionCalls/NestedCall")
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        let code = translate(&[("Sys", crate::vm::EXTENDED_SYS)]).unwrap();
+        let ram = ram_dump(&compile_and_run("ExtendedCommands", &code));
+        let statics = (16..21)
+            .map(|address| ram.get(&address).copied().unwrap_or(0))
+            .collect::<Vec<i16>>();
+        assert_eq!(statics, crate::vm::EXTENDED_STATICS);
+    }
+
+    #[test]
+    fn test_bad_address_panics() {
+        let code = translate(&[(
+            "Main",
+            "push constant 1\n\
+             neg\n\
+             pop pointer 1\n\
+             push that 0",
+        )])
+        .unwrap();
+        let output = compile_and_run("BadAddress", &code);
+        assert!(!output.status.success());
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("address -1 is outside of the ram")
+        );
+    }
+}
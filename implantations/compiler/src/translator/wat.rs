@@ -0,0 +1,442 @@
+use crate::vm::{self, Command, Segment};
+use std::collections::{HashMap, HashSet};
+
+const PRELUDE: &str = r#"(module
+  ;; ram word i lives at byte 2 * i, one 64KiB page holds all 32K words
+  (memory (export "memory") 1)
+  (global (export "SCREEN") i32 (i32.const 32768))
+  (global (export "KBD") i32 (i32.const 49152))
+  ;; when set, every jump uses one unit and running out traps, 0 runs forever
+  (global $fuel (export "fuel") (mut i32) (i32.const 0))
+
+  (func $tick
+    (if (global.get $fuel)
+      (then
+        (global.set $fuel (i32.sub (global.get $fuel) (i32.const 1)))
+        (if (i32.eqz (global.get $fuel)) (then unreachable)))))
+
+  (func $peek (param $address i32) (result i32)
+    (i32.load16_s
+      (i32.shl (i32.and (local.get $address) (i32.const 0x7fff)) (i32.const 1))))
+
+  (func $poke (param $address i32) (param $value i32)
+    (i32.store16
+      (i32.shl (i32.and (local.get $address) (i32.const 0x7fff)) (i32.const 1))
+      (local.get $value)))
+
+  (func $push (param $value i32)
+    (call $poke (call $peek (i32.const 0)) (local.get $value))
+    (call $poke (i32.const 0) (i32.add (call $peek (i32.const 0)) (i32.const 1))))
+
+  (func $pop (result i32)
+    (call $poke (i32.const 0) (i32.sub (call $peek (i32.const 0)) (i32.const 1)))
+    (call $peek (call $peek (i32.const 0))))
+
+  ;; everything of `call f n` except the jump, the return address lives on the wasm stack
+  (func $call_frame (param $n_args i32)
+    (call $push (i32.const 0))
+    (call $push (call $peek (i32.const 1)))
+    (call $push (call $peek (i32.const 2)))
+    (call $push (call $peek (i32.const 3)))
+    (call $push (call $peek (i32.const 4)))
+    (call $poke (i32.const 2)
+      (i32.sub (call $peek (i32.const 0)) (i32.add (local.get $n_args) (i32.const 5))))
+    (call $poke (i32.const 1) (call $peek (i32.const 0))))
+
+  (func $return_frame
+    (local $frame i32)
+    (local.set $frame (call $peek (i32.const 1)))
+    (call $poke (call $peek (i32.const 2)) (call $pop))
+    (call $poke (i32.const 0) (i32.add (call $peek (i32.const 2)) (i32.const 1)))
+    (call $poke (i32.const 4) (call $peek (i32.sub (local.get $frame) (i32.const 1))))
+    (call $poke (i32.const 3) (call $peek (i32.sub (local.get $frame) (i32.const 2))))
+    (call $poke (i32.const 2) (call $peek (i32.sub (local.get $frame) (i32.const 3))))
+    (call $poke (i32.const 1) (call $peek (i32.sub (local.get $frame) (i32.const 4)))))
+"#;
+
+/// translate `(file stem, vm code)` pairs into a webassembly text module.
+///
+/// the hack ram is the exported linear memory (word `i` at byte `2 * i`, the
+/// exported `SCREEN` and `KBD` globals are byte offsets into it) and every vm
+/// function becomes a wasm function. labels inside a function are compiled to a
+/// `br_table` dispatch loop, since vm jumps are not structured. the exported `run`
+/// sets `SP = 256` and calls `Sys.init`, or runs the code outside of functions
+/// when there is no `Sys.init`.
+pub fn translate(sources: &[(&str, &str)]) -> Result<String, String> {
+    let mut functions = HashSet::new();
+    let mut files = vec![];
+    for (name, source) in sources {
+        let commands = vm::parse(source).map_err(|e| format!("{}.vm {}", name, e))?;
+        for (_, command) in &commands {
+            if let Command::Function(function_name, _) = command {
+                functions.insert(function_name.clone());
+            }
+        }
+        files.push((*name, commands));
+    }
+
+    // split every file into its functions, code before the first one is toplevel
+    let mut bodies: Vec<Body> = vec![];
+    let mut toplevel = vec![];
+    for (file, commands) in &files {
+        let mut current: Option<usize> = None;
+        for entry in commands {
+            match (&entry.1, current) {
+                (Command::Function(name, n_locals), _) => {
+                    bodies.push(Body {
+                        name: name.clone(),
+                        n_locals: *n_locals,
+                        file,
+                        commands: vec![],
+                    });
+                    current = Some(bodies.len() - 1);
+                }
+                (_, Some(index)) => bodies[index].commands.push(entry),
+                (_, None) => toplevel.push((*file, entry)),
+            }
+        }
+    }
+
+    let mut backend = WatBackend {
+        output: vec![PRELUDE.to_string()],
+        functions,
+        statics: HashMap::new(),
+    };
+    for body in &bodies {
+        let commands = body
+            .commands
+            .iter()
+            .map(|entry| (body.file, *entry))
+            .collect();
+        backend.function(&format!("${}", body.name), body.n_locals, commands)?;
+    }
+    backend.function("$toplevel", 0, toplevel)?;
+
+    backend.emit("  (func (export \"run\")");
+    backend.emit("    (call $poke (i32.const 0) (i32.const 256))");
+    if backend.functions.contains("Sys.init") {
+        backend.emit("    (call $call_frame (i32.const 0))");
+        backend.emit("    (call $Sys.init))");
+    } else {
+        backend.emit("    (call $toplevel))");
+    }
+    backend.emit(")");
+    Ok(backend.output.join("\n") + "\n")
+}
+
+struct Body<'a> {
+    name: String,
+    n_locals: u16,
+    file: &'a str,
+    commands: Vec<&'a (usize, Command)>,
+}
+
+struct WatBackend {
+    output: Vec<String>,
+    functions: HashSet<String>,
+    statics: HashMap<(String, u16), usize>,
+}
+
+impl WatBackend {
+    fn emit(&mut self, code: &str) {
+        self.output.push(code.to_string())
+    }
+
+    fn function(
+        &mut self,
+        name: &str,
+        n_locals: u16,
+        commands: Vec<(&str, &(usize, Command))>,
+    ) -> Result<(), String> {
+        // block 0 starts at the top, every label starts a new block
+        let mut blocks = HashMap::new();
+        for (file, (line, command)) in &commands {
+            if let Command::Label(label) = command {
+                if blocks.insert(label.clone(), blocks.len() + 1).is_some() {
+                    return Err(format!(
+                        "{}.vm line {}: label {} is defined twice in {}",
+                        file, line, label, name
+                    ));
+                }
+            }
+        }
+
+        self.emit(&format!("  (func {} (local $pc i32) (local $y i32)", name));
+        for _ in 0..n_locals {
+            self.emit("    i32.const 0\n    call $push");
+        }
+        self.emit("    loop $dispatch");
+        for block in (0..=blocks.len()).rev() {
+            self.emit(&format!("    block $b{}", block));
+        }
+        let targets = (0..=blocks.len())
+            .map(|block| format!("$b{}", block))
+            .collect::<Vec<String>>();
+        self.emit(&format!(
+            "    local.get $pc\n    br_table {}\n    end",
+            targets.join(" ")
+        ));
+        for (file, (line, command)) in commands {
+            self.translate(file, command, &blocks)
+                .map_err(|e| format!("{}.vm line {}: {}", file, line, e))?;
+        }
+        self.emit("    end\n  )");
+        Ok(())
+    }
+
+    fn translate(
+        &mut self,
+        file: &str,
+        command: &Command,
+        blocks: &HashMap<String, usize>,
+    ) -> Result<(), String> {
+        let jump = |label: &str| -> Result<String, String> {
+            let block = blocks
+                .get(label)
+                .ok_or(format!("label {} is not defined", label))?;
+            Ok(format!(
+                "call $tick\n    i32.const {}\n    local.set $pc\n    br $dispatch",
+                block
+            ))
+        };
+        let code = match command {
+            Command::Push(Segment::Constant, i) => format!("i32.const {}\n    call $push", i),
+            Command::Push(segment, i) => format!(
+                "{}\n    call $peek\n    call $push",
+                self.address(file, *segment, *i)?
+            ),
+            Command::Pop(segment, i) => format!(
+                "{}\n    call $pop\n    call $poke",
+                self.address(file, *segment, *i)?
+            ),
+            Command::Move(from, i, to, j) => {
+                let value = match from {
+                    Segment::Constant => format!("i32.const {}", i),
+                    _ => format!("{}\n    call $peek", self.address(file, *from, *i)?),
+                };
+                format!(
+                    "{}\n    {}\n    call $poke",
+                    self.address(file, *to, *j)?,
+                    value
+                )
+            }
+            Command::Add => binary("i32.add"),
+            Command::Sub => binary("i32.sub"),
+            Command::And => binary("i32.and"),
+            Command::Or => binary("i32.or"),
+            Command::Eq => binary("i32.eq\n    i32.const -1\n    i32.mul"),
+            Command::Gt => binary("i32.gt_s\n    i32.const -1\n    i32.mul"),
+            Command::Lt => binary("i32.lt_s\n    i32.const -1\n    i32.mul"),
+            Command::Mul => binary("i32.mul"),
+            // divide by 1 instead of 0 to not trap, then pick 0
+            Command::Div => binary(
+                "local.get $y\n    i32.eqz\n    i32.or\n    i32.div_s\n    \
+                 i32.const 0\n    local.get $y\n    select",
+            ),
+            Command::Shl => "call $pop\n    i32.const 1\n    i32.shl\n    call $push".to_string(),
+            Command::Shr => "call $pop\n    i32.const 1\n    i32.shr_s\n    call $push".to_string(),
+            Command::Neg => "i32.const 0\n    call $pop\n    i32.sub\n    call $push".to_string(),
+            Command::Not => "call $pop\n    i32.const -1\n    i32.xor\n    call $push".to_string(),
+            Command::Inc => "call $pop\n    i32.const 1\n    i32.add\n    call $push".to_string(),
+            // falls through from the previous block
+            Command::Label(label) => format!("end ;; label {}", label),
+            Command::Goto(label) => jump(label)?,
+            Command::IfGoto(label) => format!("call $pop\n    if\n    {}\n    end", jump(label)?),
+            Command::IfNotGoto(label) => format!(
+                "call $pop\n    i32.const -1\n    i32.ne\n    if\n    {}\n    end",
+                jump(label)?
+            ),
+            Command::Function(..) => unreachable!("functions are split before translating"),
+            Command::Call(name, n_args) => {
+                if !self.functions.contains(name) {
+                    return Err(format!("function {} is not defined", name));
+                }
+                format!(
+                    "i32.const {}\n    call $call_frame\n    call ${}",
+                    n_args, name
+                )
+            }
+            Command::Return => "call $return_frame\n    return".to_string(),
+        };
+        self.emit(&format!("    {}", code));
+        Ok(())
+    }
+
+    /// instructions leaving the ram address of segment[i] on the wasm stack
+    fn address(&mut self, file: &str, segment: Segment, i: u16) -> Result<String, String> {
+        let pointer = |base: u16| {
+            format!(
+                "i32.const {}\n    call $peek\n    i32.const {}\n    i32.add",
+                base, i
+            )
+        };
+        Ok(match segment {
+            Segment::Local => pointer(1),
+            Segment::Argument => pointer(2),
+            Segment::This => pointer(3),
+            Segment::That => pointer(4),
+            Segment::Pointer if i < 2 => format!("i32.const {}", 3 + i),
+            Segment::Temp if i < 8 => format!("i32.const {}", 5 + i),
+            Segment::Static => {
+                let next = 16 + self.statics.len();
+                let address = *self.statics.entry((file.to_string(), i)).or_insert(next);
+                format!("i32.const {}", address)
+            }
+            _ => return Err(format!("{} {} is not addressable", segment.as_str(), i)),
+        })
+    }
+}
+
+fn binary(op: &str) -> String {
+    format!(
+        "call $pop\n    local.set $y\n    call $pop\n    local.get $y\n    {}\n    call $push",
+        op
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_module_shape() {
+        let wat = translate(&[(
+            "Sys",
+            "function Sys.init 1\n\
+             label LOOP\n\
+             push local 0\n\
+             if-goto LOOP\n\
+             goto END\n\
+             label END\n\
+             return",
+        )])
+        .unwrap();
+        assert!(wat.starts_with("(module"));
+        assert!(wat.contains("(memory (export \"memory\") 1)"));
+        assert!(wat.contains("(func $Sys.init (local $pc i32) (local $y i32)"));
+        assert!(wat.contains("br_table $b0 $b1 $b2"));
+        assert!(wat.contains("(call $Sys.init))"));
+        assert_eq!(wat.matches('(').count(), wat.matches(')').count());
+    }
+
+    #[test]
+    fn test_exact_output() {
+        let wat = translate(&[(
+            "Main",
+            "function Main.f 1\n\
+             push argument 0\n\
+             pop static 2\n\
+             label LOOP\n\
+             push static 2\n\
+             push constant 1\n\
+             sub\n\
+             if-goto LOOP\n\
+             push local 0\n\
+             return",
+        )])
+        .unwrap();
+        let expected = r#"  (func $Main.f (local $pc i32) (local $y i32)
+    i32.const 0
+    call $push
+    loop $dispatch
+    block $b1
+    block $b0
+    local.get $pc
+    br_table $b0 $b1
+    end
+    i32.const 2
+    call $peek
+    i32.const 0
+    i32.add
+    call $peek
+    call $push
+    i32.const 16
+    call $pop
+    call $poke
+    end ;; label LOOP
+    i32.const 16
+    call $peek
+    call $push
+    i32.const 1
+    call $push
+    call $pop
+    local.set $y
+    call $pop
+    local.get $y
+    i32.sub
+    call $push
+    call $pop
+    if
+    call $tick
+    i32.const 1
+    local.set $pc
+    br $dispatch
+    end
+    i32.const 1
+    call $peek
+    i32.const 0
+    i32.add
+    call $peek
+    call $push
+    call $return_frame
+    return
+    end
+  )
+  (func $toplevel (local $pc i32) (local $y i32)
+    loop $dispatch
+    block $b0
+    local.get $pc
+    br_table $b0
+    end
+    end
+  )
+  (func (export "run")
+    (call $poke (i32.const 0) (i32.const 256))
+    (call $toplevel))
+)
+"#;
+        assert_eq!(wat.strip_prefix(&format!("{}\n", PRELUDE)), Some(expected));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(translate(&[("Main", "function Main.main 0\ncall Foo.bar 0")]).is_err());
+        assert!(translate(&[("Main", "function Main.main 0\ngoto NOWHERE")]).is_err());
+    }
+
+    /// assemble with `wat2wasm` and run with `node`, `cargo test -- --ignored` runs it
+    #[test]
+    #[ignore = "needs wat2wasm and node"]
+    fn test_statics_test() {
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/08/FunctionCalls/StaticsTest");
+        let sources = vm::load_sources(&vm_path).unwrap();
+        let wat = translate(&vm::as_str_pairs(&sources)).unwrap();
+        let out_dir = std::env::temp_dir().join("nand2tetris-wat");
+        std::fs::create_dir_all(&out_dir).expect("failed to create dir");
+        std::fs::write(out_dir.join("StaticsTest.wat"), wat).expect("failed to write file");
+        let status = std::process::Command::new("wat2wasm")
+            .arg(out_dir.join("StaticsTest.wat"))
+            .arg("-o")
+            .arg(out_dir.join("StaticsTest.wasm"))
+            .status()
+            .expect("failed to run wat2wasm");
+        assert!(status.success());
+        // Sys.init ends in an endless loop, so run out of fuel and read the ram after the trap
+        let script = "const fs = require('fs');\n\
+            const module = new WebAssembly.Module(fs.readFileSync(process.argv[1]));\n\
+            const { exports } = new WebAssembly.Instance(module);\n\
+            exports.fuel.value = 1000;\n\
+            try { exports.run(); } catch (e) {}\n\
+            const ram = new Int16Array(exports.memory.buffer);\n\
+            console.log([ram[0], ram[261], ram[262]].join(','));";
+        let output = std::process::Command::new("node")
+            .arg("-e")
+            .arg(script)
+            .arg(out_dir.join("StaticsTest.wasm"))
+            .output()
+            .expect("failed to run node");
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "263,-2,8");
+    }
+}
@@ -0,0 +1,591 @@
+use super::c::mangle;
+use super::static_symbol;
+use crate::vm::{self, Command, Segment};
+use std::collections::HashSet;
+
+const PRELUDE: &str = r#"# hack state lives in callee saved registers so the runtime can call into libc:
+#   rbp  base of RAM, word i at byte 2 * i
+#   r12  SP    r13  LCL    r14  ARG    r15  THIS    rbx  THAT
+# they are only written back to RAM[0..4] when the program halts.
+
+    .macro VM_PUSH_AX
+    movw %ax, (%rbp,%r12,2)
+    incq %r12
+    .endm
+
+    .macro VM_POP_AX
+    decq %r12
+    movw (%rbp,%r12,2), %ax
+    .endm
+
+    # every jump counts down to the next keyboard poll and jump budget check
+    .macro VM_TICK
+    decq vm_ticks(%rip)
+    jnz 1f
+    call vm_slow_tick
+1:
+    .endm
+
+    # everything of `call f n` but the return address, which lives on the native stack
+    .macro VM_CALL function, n_args
+    movw $0, (%rbp,%r12,2)
+    movw %r13w, 2(%rbp,%r12,2)
+    movw %r14w, 4(%rbp,%r12,2)
+    movw %r15w, 6(%rbp,%r12,2)
+    movw %bx, 8(%rbp,%r12,2)
+    addq $5, %r12
+    leaq -(5+\n_args)(%r12), %r14
+    movq %r12, %r13
+    call \function
+    .endm
+
+    .text
+vm_return:
+    movq %r13, %rcx
+    VM_POP_AX
+    movw %ax, (%rbp,%r14,2)
+    leaq 1(%r14), %r12
+    movswq -2(%rbp,%rcx,2), %rbx
+    movswq -4(%rbp,%rcx,2), %r15
+    movswq -6(%rbp,%rcx,2), %r14
+    movswq -8(%rbp,%rcx,2), %r13
+    ret
+
+# poll stdin without blocking and put the key into KBD, 0 when nothing is pressed.
+# define VM_MAX_JUMPS (`cc -Wa,--defsym,VM_MAX_JUMPS=n`) to halt after about n jumps.
+vm_slow_tick:
+    movq $4096, vm_ticks(%rip)
+    .ifdef VM_MAX_JUMPS
+    subq $4096, vm_budget(%rip)
+    jle vm_halt
+    .endif
+    movq %rsp, vm_saved_rsp(%rip)
+    andq $-16, %rsp
+    leaq vm_pollfd(%rip), %rdi
+    movl $1, %esi
+    xorl %edx, %edx
+    call poll@PLT
+    movw $0, 2*24576(%rbp)
+    testl %eax, %eax
+    jle 2f
+    xorl %edi, %edi
+    leaq vm_key(%rip), %rsi
+    movl $1, %edx
+    call read@PLT
+    cmpq $1, %rax
+    jne 2f
+    movzbl vm_key(%rip), %eax
+    # hack key codes for newline and backspace
+    cmpl $10, %eax
+    jne 1f
+    movl $128, %eax
+1:  cmpl $127, %eax
+    jne 1f
+    movl $129, %eax
+1:  movw %ax, 2*24576(%rbp)
+2:  movq vm_saved_rsp(%rip), %rsp
+    ret
+
+# write the registers back, dump the screen to argv[1] if given and print the
+# non zero ram below the screen.
+vm_halt:
+    movw %r12w, 0(%rbp)
+    movw %r13w, 2(%rbp)
+    movw %r14w, 4(%rbp)
+    movw %r15w, 6(%rbp)
+    movw %bx, 8(%rbp)
+    andq $-16, %rsp
+    cmpl $2, vm_argc(%rip)
+    jl 1f
+    movq vm_argv(%rip), %rax
+    movq 8(%rax), %rdi
+    call vm_write_screen
+1:  xorl %r12d, %r12d
+2:  movswl (%rbp,%r12,2), %edx
+    testl %edx, %edx
+    jz 3f
+    leaq vm_dump_format(%rip), %rdi
+    movl %r12d, %esi
+    xorl %eax, %eax
+    call printf@PLT
+3:  incq %r12
+    cmpq $16384, %r12
+    jb 2b
+    xorl %edi, %edi
+    call exit@PLT
+
+# the 512x256 screen as a binary ppm, set bits are black
+vm_write_screen:
+    pushq %rbx
+    leaq vm_wb(%rip), %rsi
+    call fopen@PLT
+    testq %rax, %rax
+    jz 3f
+    movq %rax, %rbx
+    movq %rax, %rdi
+    leaq vm_ppm_header(%rip), %rsi
+    xorl %eax, %eax
+    call fprintf@PLT
+    leaq vm_pixels(%rip), %rdi
+    xorl %r8d, %r8d
+1:  movl %r8d, %eax
+    shrl $4, %eax
+    movzwl 2*16384(%rbp,%rax,2), %edx
+    movl %r8d, %ecx
+    andl $15, %ecx
+    shrl %cl, %edx
+    andl $1, %edx
+    decl %edx
+    movb %dl, (%rdi)
+    movb %dl, 1(%rdi)
+    movb %dl, 2(%rdi)
+    addq $3, %rdi
+    incl %r8d
+    cmpl $512*256, %r8d
+    jb 1b
+    leaq vm_pixels(%rip), %rdi
+    movl $1, %esi
+    movl $512*256*3, %edx
+    movq %rbx, %rcx
+    call fwrite@PLT
+    movq %rbx, %rdi
+    call fclose@PLT
+3:  popq %rbx
+    ret
+
+    .globl main
+main:
+    pushq %rbx
+    pushq %rbp
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    subq $8, %rsp
+    movl %edi, vm_argc(%rip)
+    movq %rsi, vm_argv(%rip)
+    leaq RAM(%rip), %rbp
+    movq $256, %r12
+    xorl %r13d, %r13d
+    xorl %r14d, %r14d
+    xorl %r15d, %r15d
+    xorl %ebx, %ebx
+    call vm_run
+    jmp vm_halt
+
+    .data
+vm_ticks: .quad 4096
+    .ifdef VM_MAX_JUMPS
+vm_budget: .quad VM_MAX_JUMPS
+    .endif
+vm_pollfd: .long 0
+    .short 1, 0
+
+    .bss
+    .align 16
+RAM: .zero 2*32768
+vm_pixels: .zero 512*256*3
+vm_saved_rsp: .quad 0
+vm_argv: .quad 0
+vm_argc: .long 0
+vm_key: .byte 0
+
+    .section .rodata
+vm_dump_format: .asciz "RAM[%d] = %d\n"
+vm_ppm_header: .asciz "P6\n512 256\n255\n"
+vm_wb: .asciz "wb"
+
+    .section .note.GNU-stack,"",@progbits
+"#;
+
+/// translate `(file stem, vm code)` pairs into x86-64 assembly for the gnu assembler,
+/// to be linked against libc with `cc`.
+///
+/// the hack ram is a static `RAM` array, while SP, LCL, ARG, THIS and THAT are
+/// kept in registers and the return addresses on the native stack. statics use
+/// the same `File.i` symbols as the hack translator, placed from 16 on in order of
+/// first use, so ram dumps match the cpu emulator's. the runtime polls stdin into
+/// `KBD` and can write the screen as a ppm image when it halts. without
+/// `Sys.init` the code outside of functions runs instead.
+pub fn translate(sources: &[(&str, &str)]) -> Result<String, String> {
+    let mut files = vec![];
+    for (name, source) in sources {
+        let commands = vm::parse(source).map_err(|e| format!("{}.vm {}", name, e))?;
+        files.push((*name, commands));
+    }
+    let mut backend = X86Backend::default();
+    for (_, commands) in &files {
+        for (_, command) in commands {
+            if let Command::Function(name, _) = command {
+                backend.functions.insert(name.clone());
+            }
+        }
+    }
+    backend.emit(PRELUDE);
+    backend.emit("    .text");
+
+    let mut toplevel = vec![];
+    for (file, commands) in &files {
+        backend.file = file.to_string();
+        let mut in_function = false;
+        for (line, command) in commands {
+            in_function |= matches!(command, Command::Function(..));
+            if in_function {
+                backend
+                    .translate(command)
+                    .map_err(|e| format!("{}.vm line {}: {}", file, line, e))?;
+            } else {
+                toplevel.push((file.to_string(), *line, command.clone()));
+            }
+        }
+    }
+
+    backend.function = "vm_toplevel".to_string();
+    backend.emit("\nvm_toplevel:");
+    for (file, line, command) in &toplevel {
+        backend.file = file.to_string();
+        backend
+            .translate(command)
+            .map_err(|e| format!("{}.vm line {}: {}", file, line, e))?;
+    }
+    backend.emit("    ret");
+
+    backend.emit("\nvm_run:");
+    if backend.functions.contains("Sys.init") {
+        backend.emit(&format!("    VM_CALL {}, 0", mangle("Sys.init")));
+    } else {
+        backend.emit("    call vm_toplevel");
+    }
+    backend.emit("    ret\n");
+    for (address, symbol) in backend.statics.iter().enumerate() {
+        backend
+            .output
+            .push(format!("    .set {}, {}", symbol, 16 + address));
+    }
+    Ok(backend.output.join("\n") + "\n")
+}
+
+#[derive(Default)]
+struct X86Backend {
+    output: Vec<String>,
+    functions: HashSet<String>,
+    /// static symbols in order of first use
+    statics: Vec<String>,
+    file: String,
+    /// mangled name of the current function, labels are local to it
+    function: String,
+}
+
+impl X86Backend {
+    fn emit(&mut self, code: &str) {
+        self.output.push(code.to_string())
+    }
+
+    fn translate(&mut self, command: &Command) -> Result<(), String> {
+        let code = match command {
+            Command::Push(Segment::Constant, i) => {
+                format!("movw ${}, (%rbp,%r12,2)\n    incq %r12", i)
+            }
+            Command::Push(segment, i) => {
+                let (setup, operand) = self.address(*segment, *i, "%rax")?;
+                format!("{}\n    movw {}, %ax\n    VM_PUSH_AX", setup, operand)
+            }
+            Command::Pop(segment, i) => {
+                let (setup, operand) = self.address(*segment, *i, "%rcx")?;
+                format!("{}\n    VM_POP_AX\n    movw %ax, {}", setup, operand)
+            }
+            Command::Move(from, i, to, j) => {
+                let load = match from {
+                    Segment::Constant => format!("movw ${}, %ax", i),
+                    _ => {
+                        let (setup, operand) = self.address(*from, *i, "%rax")?;
+                        format!("{}\n    movw {}, %ax", setup, operand)
+                    }
+                };
+                let (setup, operand) = self.address(*to, *j, "%rcx")?;
+                format!("{}\n    {}\n    movw %ax, {}", load, setup, operand)
+            }
+            Command::Add => binary("addw %ax, -2(%rbp,%r12,2)"),
+            Command::Sub => binary("subw %ax, -2(%rbp,%r12,2)"),
+            Command::And => binary("andw %ax, -2(%rbp,%r12,2)"),
+            Command::Or => binary("orw %ax, -2(%rbp,%r12,2)"),
+            Command::Eq => compare("sete"),
+            Command::Gt => compare("setg"),
+            Command::Lt => compare("setl"),
+            Command::Mul => binary("imulw -2(%rbp,%r12,2), %ax\n    movw %ax, -2(%rbp,%r12,2)"),
+            // idiv traps on 0 and on -32768 / -1
+            Command::Div => binary(
+                "movw %ax, %cx\n    \
+                 movw -2(%rbp,%r12,2), %ax\n    \
+                 testw %cx, %cx\n    \
+                 jz 1f\n    \
+                 cmpw $-1, %cx\n    \
+                 je 2f\n    \
+                 cwtd\n    \
+                 idivw %cx\n    \
+                 jmp 3f\n\
+                 1:\n    \
+                 xorw %ax, %ax\n    \
+                 jmp 3f\n\
+                 2:\n    \
+                 negw %ax\n\
+                 3:\n    \
+                 movw %ax, -2(%rbp,%r12,2)",
+            ),
+            Command::Shl => "shlw -2(%rbp,%r12,2)".to_string(),
+            Command::Shr => "sarw -2(%rbp,%r12,2)".to_string(),
+            Command::Neg => "negw -2(%rbp,%r12,2)".to_string(),
+            Command::Not => "notw -2(%rbp,%r12,2)".to_string(),
+            Command::Inc => "incw -2(%rbp,%r12,2)".to_string(),
+            Command::Label(label) => {
+                self.emit(&format!("{}:", self.label(label)));
+                return Ok(());
+            }
+            Command::Goto(label) => format!("VM_TICK\n    jmp {}", self.label(label)),
+            Command::IfGoto(label) => format!(
+                "VM_TICK\n    VM_POP_AX\n    testw %ax, %ax\n    jnz {}",
+                self.label(label)
+            ),
+            Command::IfNotGoto(label) => format!(
+                "VM_TICK\n    VM_POP_AX\n    cmpw $-1, %ax\n    jne {}",
+                self.label(label)
+            ),
+            Command::Function(name, n_locals) => {
+                self.function = mangle(name);
+                self.emit(&format!("\n# function {}\n{}:", name, self.function));
+                if *n_locals == 0 {
+                    return Ok(());
+                }
+                format!(
+                    "movl ${}, %ecx\n\
+                     1:  movw $0, (%rbp,%r12,2)\n    \
+                     incq %r12\n    \
+                     loop 1b",
+                    n_locals
+                )
+            }
+            Command::Call(name, n_args) => {
+                if !self.functions.contains(name) {
+                    return Err(format!("function {} is not defined", name));
+                }
+                format!("VM_CALL {}, {}", mangle(name), n_args)
+            }
+            Command::Return => "jmp vm_return".to_string(),
+        };
+        self.emit(&format!("    {}", code));
+        Ok(())
+    }
+
+    fn label(&self, label: &str) -> String {
+        format!(".L{}.{}", self.function, mangle(label))
+    }
+
+    /// instructions computing the address of segment[i] into `scratch`, if needed,
+    /// and the memory operand for it.
+    fn address(
+        &mut self,
+        segment: Segment,
+        i: u16,
+        scratch: &str,
+    ) -> Result<(String, String), String> {
+        let pointer = |register: &str| {
+            (
+                String::new(),
+                format!("{}(%rbp,{},2)", 2 * i as u32, register),
+            )
+        };
+        // this and that hold arbitrary values, so keep them inside the ram
+        let wrapped = |register: &str| {
+            let scratch32 = scratch.replace("%r", "%e");
+            (
+                format!(
+                    "leaq {}({}), {}\n    andl $0x7fff, {}",
+                    i, register, scratch, scratch32
+                ),
+                format!("(%rbp,{},2)", scratch),
+            )
+        };
+        Ok(match segment {
+            Segment::Local => pointer("%r13"),
+            Segment::Argument => pointer("%r14"),
+            Segment::This => wrapped("%r15"),
+            Segment::That => wrapped("%rbx"),
+            Segment::Pointer if i == 0 => (String::new(), "%r15w".to_string()),
+            Segment::Pointer if i == 1 => (String::new(), "%bx".to_string()),
+            Segment::Temp if i < 8 => (String::new(), format!("{}(%rbp)", 2 * (5 + i))),
+            Segment::Static => {
+                let symbol = static_symbol(&self.file, i);
+                if !self.statics.contains(&symbol) {
+                    self.statics.push(symbol.clone());
+                }
+                (String::new(), format!("2*{}(%rbp)", symbol))
+            }
+            _ => return Err(format!("{} {} is out of range", segment.as_str(), i)),
+        })
+    }
+}
+
+fn binary(op: &str) -> String {
+    format!("VM_POP_AX\n    {}", op)
+}
+
+fn compare(set: &str) -> String {
+    format!(
+        "VM_POP_AX\n    \
+         cmpw %ax, -2(%rbp,%r12,2)\n    \
+         {} %al\n    \
+         movzbw %al, %ax\n    \
+         negw %ax\n    \
+         movw %ax, -2(%rbp,%r12,2)",
+        set
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_errors() {
+        assert!(translate(&[("Main", "function Main.main 0\ncall Foo.bar 0")]).is_err());
+        assert!(translate(&[("Main", "function Main.main 0\npush temp 8")]).is_err());
+    }
+
+    #[test]
+    fn test_statics_are_shared_symbols() {
+        let asm =
+            translate(&[("A", "push static 1\npop static 0"), ("B", "push static 0")]).unwrap();
+        assert!(asm.contains("movw 2*A.1(%rbp), %ax"));
+        assert!(asm.contains("    .set A.1, 16\n    .set A.0, 17\n    .set B.0, 18\n"));
+    }
+
+    fn native() -> bool {
+        cfg!(all(target_arch = "x86_64", target_os = "linux"))
+    }
+
+    /// assemble and link with `cc`, run with `args` and return the ram dump
+    fn compile_and_run(name: &str, asm: &str, args: &[&Path]) -> HashMap<usize, i16> {
+        let out_dir = std::env::temp_dir().join("nand2tetris-x86").join(name);
+        std::fs::create_dir_all(&out_dir).expect("failed to create dir");
+        let asm_path = out_dir.join("out.s");
+        let exe_path = out_dir.join("out");
+        std::fs::write(&asm_path, asm).expect("failed to write file");
+        let status = std::process::Command::new("cc")
+            .args(["-Wa,--defsym,VM_MAX_JUMPS=100000", "-o"])
+            .arg(&exe_path)
+            .arg(&asm_path)
+            .status()
+            .expect("failed to run cc");
+        assert!(status.success());
+        let output = std::process::Command::new(&exe_path)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .expect("failed to run compiled program");
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| {
+                let (address, value) = line.split_at(line.find(" = ").unwrap());
+                (
+                    address[4..address.len() - 1].parse().unwrap(),
+                    value[3..].parse().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// compare the first row of the project's `.cmp` file with the native run
+    fn run_project(name: &str) {
+        if !native() {
+            return;
+        }
+        let vm_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../../projects/")
+            .join(name);
+        let filename = vm_path.file_name().unwrap().to_string_lossy().to_string();
+        let sources = vm::load_sources(&vm_path).unwrap();
+        let asm = translate(&vm::as_str_pairs(&sources)).unwrap();
+        let ram = compile_and_run(name, &asm, &[]);
+
+        let cmp = std::fs::read_to_string(vm_path.join(format!("{}.cmp", filename)))
+            .expect("failed to read compare file");
+        let mut rows = cmp.lines().map(|line| {
+            line.split('|')
+                .filter(|s| !s.trim().is_empty())
+                .map(|s| s.trim().to_string())
+                .collect::<Vec<String>>()
+        });
+        let (header, values) = (rows.next().unwrap(), rows.next().unwrap());
+        for (column, value) in header.iter().zip(values) {
+            let address: usize = column[4..column.len() - 1].parse().unwrap();
+            let expected: i16 = value.parse().unwrap();
+            assert_eq!(
+                ram.get(&address).copied().unwrap_or(0),
+                expected,
+                "RAM[{}]",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn test_fibonacci_element() {
+        run_project("08/FunctionCalls/FibonacciElement")
+    }
+
+    #[test]
+    fn test_statics_test() {
+        run_project("08/FunctionCalls/StaticsTest")
+    }
+
+    #[test]
+    fn test_nested_call() {
+        run_project("08/FunctionCalls/NestedCall")
+    }
+
+    #[test]
+    fn test_extended_commands() {
+        if !native() {
+            return;
+        }
+        let asm = translate(&[("Sys", crate::vm::EXTENDED_SYS)]).unwrap();
+        let ram = compile_and_run("ExtendedCommands", &asm, &[]);
+        let statics = (16..21)
+            .map(|address| ram.get(&address).copied().unwrap_or(0))
+            .collect::<Vec<i16>>();
+        assert_eq!(statics, crate::vm::EXTENDED_STATICS);
+    }
+
+    #[test]
+    fn test_screen_dump() {
+        if !native() {
+            return;
+        }
+        let asm = translate(&[(
+            "Sys",
+            "function Sys.init 0\n\
+             push constant 16384\n\
+             pop pointer 1\n\
+             push constant 5\n\
+             pop that 0\n\
+             label HALT\n\
+             goto HALT",
+        )])
+        .unwrap();
+        let ppm_path = std::env::temp_dir().join("nand2tetris-x86-screen.ppm");
+        let ram = compile_and_run("screen", &asm, &[&ppm_path]);
+        assert_eq!(ram.get(&4), Some(&16384));
+        let ppm = std::fs::read(&ppm_path).expect("failed to read ppm");
+        let header = b"P6\n512 256\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 512 * 256 * 3);
+        // bits 0 and 2 of the first word are the first and third pixel
+        let pixels = &ppm[header.len()..];
+        assert_eq!(
+            &pixels[..12],
+            &[0, 0, 0, 255, 255, 255, 0, 0, 0, 255, 255, 255]
+        );
+    }
+}
@@ -0,0 +1,373 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Argument,
+    Local,
+    Static,
+    Constant,
+    This,
+    That,
+    Pointer,
+    Temp,
+}
+
+impl FromStr for Segment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argument" => Ok(Segment::Argument),
+            "local" => Ok(Segment::Local),
+            "static" => Ok(Segment::Static),
+            "constant" => Ok(Segment::Constant),
+            "this" => Ok(Segment::This),
+            "that" => Ok(Segment::That),
+            "pointer" => Ok(Segment::Pointer),
+            "temp" => Ok(Segment::Temp),
+            _ => Err(format!("unknown segment {}", s)),
+        }
+    }
+}
+
+impl Segment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Segment::Argument => "argument",
+            Segment::Local => "local",
+            Segment::Static => "static",
+            Segment::Constant => "constant",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Pointer => "pointer",
+            Segment::Temp => "temp",
+        }
+    }
+}
+
+/// a single vm command, including the ones only produced by optimization passes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Push(Segment, u16),
+    Pop(Segment, u16),
+    Add,
+    Sub,
+    Neg,
+    Eq,
+    Gt,
+    Lt,
+    And,
+    Or,
+    Not,
+    Label(String),
+    Goto(String),
+    IfGoto(String),
+    Function(String, u16),
+    Call(String, u16),
+    Return,
+    // extended commands, the hack translator only accepts them when enabled
+    /// `x * y`, wrapping around
+    Mul,
+    /// `x / y` rounded towards zero, `x / 0` is 0
+    Div,
+    /// `x << 1`
+    Shl,
+    /// `x >> 1`, keeping the sign
+    Shr,
+    // produced by the peephole optimizer, not accepted in vm source
+    /// `push constant 1` followed by `add`
+    Inc,
+    /// `push` followed by `pop` without touching the stack
+    Move(Segment, u16, Segment, u16),
+    /// `not` followed by `if-goto`, jumps unless the value is -1 rather than 0
+    IfNotGoto(String),
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let index = |i: usize| -> Result<u16, String> {
+            let part = parts
+                .get(i)
+                .ok_or(format!("missing operand in `{}`", line))?;
+            part.parse::<u16>()
+                .map_err(|_| format!("invalid number {} in `{}`", part, line))
+        };
+        let name = |i: usize| -> Result<String, String> {
+            parts
+                .get(i)
+                .map(|s| s.to_string())
+                .ok_or(format!("missing operand in `{}`", line))
+        };
+        // segment[i] at `parts[at]` and `parts[at + 1]`, checked against what hack can address
+        let operand = |at: usize, written: bool| -> Result<(Segment, u16), String> {
+            let segment = name(at)?.parse::<Segment>()?;
+            let i = index(at + 1)?;
+            let limit = match segment {
+                Segment::Constant if written => {
+                    return Err(format!("cannot write to constant in `{}`", line))
+                }
+                Segment::Constant => 32767,
+                Segment::Pointer => 1,
+                Segment::Temp => 7,
+                _ => u16::MAX,
+            };
+            if i > limit {
+                return Err(format!(
+                    "{} {} is out of range in `{}`",
+                    segment.as_str(),
+                    i,
+                    line
+                ));
+            }
+            Ok((segment, i))
+        };
+        let command = match *parts.first().ok_or("empty command")? {
+            "push" => {
+                let (segment, i) = operand(1, false)?;
+                Command::Push(segment, i)
+            }
+            "pop" => {
+                let (segment, i) = operand(1, true)?;
+                Command::Pop(segment, i)
+            }
+            "add" => Command::Add,
+            "sub" => Command::Sub,
+            "neg" => Command::Neg,
+            "eq" => Command::Eq,
+            "gt" => Command::Gt,
+            "lt" => Command::Lt,
+            "and" => Command::And,
+            "or" => Command::Or,
+            "not" => Command::Not,
+            "label" => Command::Label(name(1)?),
+            "goto" => Command::Goto(name(1)?),
+            "if-goto" => Command::IfGoto(name(1)?),
+            "function" => Command::Function(name(1)?, index(2)?),
+            "call" => Command::Call(name(1)?, index(2)?),
+            "return" => Command::Return,
+            "mul" => Command::Mul,
+            "div" => Command::Div,
+            "shl" => Command::Shl,
+            "shr" => Command::Shr,
+            unknown => return Err(format!("unknown command {}", unknown)),
+        };
+        Ok(command)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Push(segment, index) => write!(f, "push {} {}", segment.as_str(), index),
+            Command::Pop(segment, index) => write!(f, "pop {} {}", segment.as_str(), index),
+            Command::Add => write!(f, "add"),
+            Command::Sub => write!(f, "sub"),
+            Command::Neg => write!(f, "neg"),
+            Command::Eq => write!(f, "eq"),
+            Command::Gt => write!(f, "gt"),
+            Command::Lt => write!(f, "lt"),
+            Command::And => write!(f, "and"),
+            Command::Or => write!(f, "or"),
+            Command::Not => write!(f, "not"),
+            Command::Label(label) => write!(f, "label {}", label),
+            Command::Goto(label) => write!(f, "goto {}", label),
+            Command::IfGoto(label) => write!(f, "if-goto {}", label),
+            Command::Function(name, n) => write!(f, "function {} {}", name, n),
+            Command::Call(name, n) => write!(f, "call {} {}", name, n),
+            Command::Return => write!(f, "return"),
+            Command::Mul => write!(f, "mul"),
+            Command::Div => write!(f, "div"),
+            Command::Shl => write!(f, "shl"),
+            Command::Shr => write!(f, "shr"),
+            Command::Inc => write!(f, "inc"),
+            Command::Move(from, i, to, j) => {
+                write!(f, "move {} {} {} {}", from.as_str(), i, to.as_str(), j)
+            }
+            Command::IfNotGoto(label) => write!(f, "if-not-goto {}", label),
+        }
+    }
+}
+
+/// strip comments and blank lines, keeping the 1-based line number of each command.
+pub fn code_lines(source: &str) -> impl Iterator<Item = (usize, &str)> {
+    source.lines().enumerate().filter_map(|(i, raw_line)| {
+        let line = raw_line.split("//").next().unwrap_or("").trim();
+        if line.is_empty() {
+            None
+        } else {
+            Some((i + 1, line))
+        }
+    })
+}
+
+/// parse a whole `.vm` source into commands tagged with their line number.
+pub fn parse(source: &str) -> Result<Vec<(usize, Command)>, String> {
+    code_lines(source)
+        .map(|(number, line)| {
+            line.parse::<Command>()
+                .map(|command| (number, command))
+                .map_err(|e| format!("line {}: {}", number, e))
+        })
+        .collect()
+}
+
+/// every `.vm` file of a directory sorted by file name, or `path` itself for a file.
+pub fn vm_paths(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut paths = std::fs::read_dir(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .map(|e| e.unwrap().path())
+        .filter(|p| match p.extension() {
+            Some(ext) => ext.to_str() == Some("vm"),
+            _ => false,
+        })
+        .collect::<Vec<PathBuf>>();
+    paths.sort();
+    Ok(paths)
+}
+
+/// `program` followed by every `library` file whose class the program does not
+/// define itself, so programs can override single os classes.
+pub fn link_library(program: Vec<PathBuf>, library: Vec<PathBuf>) -> Vec<PathBuf> {
+    let stems = program
+        .iter()
+        .map(|path| path.file_stem().map(|stem| stem.to_os_string()))
+        .collect::<Vec<_>>();
+    let library = library
+        .into_iter()
+        .filter(|path| !stems.contains(&path.file_stem().map(|stem| stem.to_os_string())));
+    program.into_iter().chain(library).collect()
+}
+
+/// read files as `(file stem, source)` pairs.
+pub fn read_sources(paths: &[PathBuf]) -> Result<Vec<(String, String)>, String> {
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            std::fs::read_to_string(path)
+                .map(|source| (name, source))
+                .map_err(|e| format!("{}: {}", path.display(), e))
+        })
+        .collect()
+}
+
+/// read a single `.vm` file or every `.vm` file of a directory as
+/// `(file stem, source)` pairs, sorted by file name.
+pub fn load_sources(path: &Path) -> Result<Vec<(String, String)>, String> {
+    read_sources(&vm_paths(path)?)
+}
+
+/// borrow `(name, source)` pairs the way the `from_sources` style apis take them.
+pub fn as_str_pairs(sources: &[(String, String)]) -> Vec<(&str, &str)> {
+    sources
+        .iter()
+        .map(|(name, source)| (name.as_str(), source.as_str()))
+        .collect()
+}
+
+/// a `Sys.init` dividing with a negative divisor and by 0, multiplying past 16 bits
+/// and shifting the sign bit, leaving `EXTENDED_STATICS` in static 0 to 4.
+#[cfg(test)]
+pub(crate) const EXTENDED_SYS: &str = "function Sys.init 0\n\
+                                       push constant 17\n\
+                                       push constant 5\n\
+                                       neg\n\
+                                       div\n\
+                                       pop static 0\n\
+                                       push constant 300\n\
+                                       push constant 300\n\
+                                       mul\n\
+                                       pop static 1\n\
+                                       push constant 7\n\
+                                       push constant 0\n\
+                                       div\n\
+                                       pop static 2\n\
+                                       push constant 5\n\
+                                       neg\n\
+                                       shr\n\
+                                       pop static 3\n\
+                                       push constant 16384\n\
+                                       shl\n\
+                                       pop static 4\n\
+                                       label HALT\n\
+                                       goto HALT";
+
+#[cfg(test)]
+pub(crate) const EXTENDED_STATICS: [i16; 5] = [-3, 24464, 0, -3, -32768];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        for line in &[
+            "push constant 7",
+            "pop local 0",
+            "add",
+            "label LOOP_START",
+            "if-goto LOOP_START",
+            "function Main.fibonacci 0",
+            "call Main.fibonacci 1",
+            "return",
+        ] {
+            assert_eq!(line.parse::<Command>().unwrap().to_string(), *line);
+        }
+        for (command, line) in &[
+            (Command::Inc, "inc"),
+            (
+                Command::Move(Segment::Argument, 0, Segment::Static, 3),
+                "move argument 0 static 3",
+            ),
+            (Command::IfNotGoto("END".to_string()), "if-not-goto END"),
+        ] {
+            assert_eq!(command.to_string(), *line);
+            assert!(line.parse::<Command>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let commands = parse("// comment\n\npush constant 1 // one\n  add\n").unwrap();
+        assert_eq!(
+            commands,
+            vec![(3, Command::Push(Segment::Constant, 1)), (4, Command::Add)]
+        );
+    }
+
+    #[test]
+    fn test_parse_error() {
+        assert!(parse("push nowhere 1").is_err());
+        assert!(parse("push constant").is_err());
+        assert!(parse("jump").is_err());
+        for line in &[
+            "pop constant 0",
+            "push constant 32768",
+            "push pointer 2",
+            "pop temp 8",
+        ] {
+            assert!(parse(line).is_err(), "{}", line);
+        }
+        assert!(parse("push constant 32767\npop pointer 1\npush temp 7").is_ok());
+    }
+
+    #[test]
+    fn test_link_library() {
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        assert_eq!(
+            link_library(
+                paths(&["game/Main.vm", "game/Math.vm"]),
+                paths(&["os/Math.vm", "os/Memory.vm", "os/Sys.vm"])
+            ),
+            paths(&["game/Main.vm", "game/Math.vm", "os/Memory.vm", "os/Sys.vm"])
+        );
+    }
+}